@@ -0,0 +1,183 @@
+use std::{env, path::{Path, PathBuf}};
+use crate::{compile, MmgxError};
+
+/// Compiles `.x` files from a `build.rs` script.
+///
+/// ```no_run
+/// let generated = mmgx::Build::new()
+///     .file("src/foo.x")
+///     .include("lib/")
+///     .compile()
+///     .unwrap();
+/// ```
+///
+/// Outputs go to `OUT_DIR` unless another directory is set, mirroring the layout of the inputs below their common directory.
+/// Every `.x` file read, including the imported ones, is reported to cargo with `cargo:rerun-if-changed`.
+pub struct Build {
+    files: Vec<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+    out_dir: Option<PathBuf>,
+    extension: String,
+    emit_header: bool,
+    private_header: bool,
+    line_directives: bool,
+    cargo_metadata: bool
+}
+
+impl Default for Build {
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Build {
+
+    pub fn new() -> Self
+    {
+        Self {
+            files: Vec::new(),
+            include_dirs: Vec::new(),
+            out_dir: None,
+            extension: String::from("c"),
+            emit_header: false,
+            private_header: false,
+            line_directives: false,
+            cargo_metadata: true
+        }
+    }
+
+    /// Adds an input file
+    pub fn file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self
+    {
+        self.files.push(path.as_ref().into());
+        self
+    }
+
+    /// Adds several input files
+    pub fn files<P: AsRef<Path>>(&mut self, paths: impl IntoIterator<Item = P>) -> &mut Self
+    {
+        self.files.extend(paths.into_iter().map(|path| path.as_ref().into()));
+        self
+    }
+
+    /// Adds a directory searched for files named by `@import`
+    pub fn include<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self
+    {
+        self.include_dirs.push(dir.as_ref().into());
+        self
+    }
+
+    /// Directory the outputs are written to, `OUT_DIR` by default
+    pub fn out_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self
+    {
+        self.out_dir = Some(dir.as_ref().into());
+        self
+    }
+
+    /// Extension of the outputs, `c` by default
+    pub fn extension(&mut self, extension: &str) -> &mut Self
+    {
+        self.extension = String::from(extension);
+        self
+    }
+
+    /// Puts the compiled modules into a header next to each output
+    pub fn emit_header(&mut self, emit: bool) -> &mut Self
+    {
+        self.emit_header = emit;
+        self
+    }
+
+    /// Puts the non-exported macros into a second `_private.h` header
+    pub fn private_header(&mut self, split: bool) -> &mut Self
+    {
+        self.private_header = split;
+        self
+    }
+
+    /// Precedes C code and defines with `#line` directives pointing into the `.x` files
+    pub fn line_directives(&mut self, emit: bool) -> &mut Self
+    {
+        self.line_directives = emit;
+        self
+    }
+
+    /// Whether to print `cargo:rerun-if-changed` lines, on by default
+    pub fn cargo_metadata(&mut self, print: bool) -> &mut Self
+    {
+        self.cargo_metadata = print;
+        self
+    }
+
+    /// Compiles all files and returns the paths of the written outputs
+    pub fn compile(&self) -> Result<Vec<PathBuf>, MmgxError>
+    {
+        let options = compile::Options {
+            extension: self.extension.clone(),
+            out_dir: self.out_dir.clone().or_else(|| env::var_os("OUT_DIR").map(PathBuf::from)),
+            output: None,
+            stdout: false,
+            make_deps: false,
+            dep_file: None,
+            include_dirs: self.include_dirs.clone(),
+            emit_header: self.emit_header,
+            private_header: self.emit_header && self.private_header,
+            line_directives: self.line_directives
+        };
+
+        // a broken input has to trigger a rerun once it is fixed
+        if self.cargo_metadata {
+            for file in self.files.iter() {
+                println!("cargo:rerun-if-changed={}", file.display());
+            }
+        }
+
+        let generated = compile::files(self.files.clone(), &options)?;
+
+        if self.cargo_metadata {
+            for source in generated.sources.iter().filter(|source| !self.files.contains(source)) {
+                println!("cargo:rerun-if-changed={}", source.display());
+            }
+        }
+        Ok(generated.outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_into_the_out_dir()
+    {
+        let dir = env::temp_dir().join(format!("mmgx-build-{}", std::process::id()));
+        let write = |path: &str, content: &str| {
+            std::fs::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(path), content).unwrap();
+        };
+        write("lib/lib.x", "@Lib {\n\tONE 1\n}\n");
+        write("src/a.x", "@A {\n\t@import \"lib.x\"\n\t@use Lib\n\t@export TWO Lib::ONE + 1\n}\n");
+        write("src/b/b.x", "int b;\n");
+
+        let out = dir.join("out");
+        let outputs = Build::new()
+            .file(dir.join("src/a.x"))
+            .file(dir.join("src/b/b.x"))
+            .include(dir.join("lib"))
+            .out_dir(&out)
+            .emit_header(true)
+            .cargo_metadata(false)
+            .compile()
+            .unwrap();
+        assert_eq!(outputs, [out.join("a.h"), out.join("a.c"), out.join("b/b.h"), out.join("b/b.c")]);
+        assert!(std::fs::read_to_string(out.join("a.h")).unwrap().contains("#define A_TWO __Lib_ONE + 1\n"));
+        assert_eq!(std::fs::read_to_string(out.join("b/b.c")).unwrap(), "int b;\n");
+        // the import is left to its own build
+        assert!(!dir.join("lib/lib.c").exists());
+
+        let err = Build::new().file(dir.join("src/missing.x")).out_dir(&out).cargo_metadata(false).compile().unwrap_err();
+        assert_eq!(err.diagnostics().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
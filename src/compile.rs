@@ -1,23 +1,81 @@
-mod parse;
-use std::{path::{Path, PathBuf}, fmt::{Display, Formatter}, fs::File, io::prelude::*, ops::{Range, RangeInclusive}, rc::Rc};
+pub(crate) mod parse;
+use crate::expand::{Step, expand_macros, same_tokens};
+use std::{path::{Component, Path, PathBuf}, fmt::{Display, Formatter}, ops::Range, fs::{File, canonicalize, create_dir_all, read, remove_file, rename}, io::prelude::*, rc::Rc};
 
-enum CompileErrorKind {
+#[derive(Debug)]
+pub enum CompileErrorKind {
     NoObjectInModule(parse::External),
     ModuleNotIncluded(String),
     NoTemplateParameters,
     TemplateParameterOutOfRange((usize, usize)),
     ErrorWhileCompiling((String, Box<CompileErrorKind>)),
     ModuleNotFound(String),
-    TemplateNotFound(String)
+    TemplateNotFound(String),
+    UnknownVariable(String),
+    DivisionByZero,
+    ArithmeticOverflow,
+    TemplateNotImplemented((String, Vec<i64>)),
+    Multiple(Vec<CompileErrorKind>),
+    InvalidStep(i64),
+    NegativeTemplateValue(i64),
+    ImportNotFound(String),
+    ImportCycle(Vec<PathBuf>),
+    DuplicateConstant(String)
 }
 
+impl CompileErrorKind {
+    // one error per failed statement, each keeping the context it failed in
+    fn flatten(self) -> Vec<CompileErrorKind>
+    {
+        match self {
+            CompileErrorKind::Multiple(errors) => errors.into_iter().flat_map(|e| e.flatten()).collect(),
+            CompileErrorKind::ErrorWhileCompiling((name, error)) => error.flatten().into_iter()
+                .map(|e| CompileErrorKind::ErrorWhileCompiling((name.clone(), Box::new(e))))
+                .collect(),
+            e => vec![e]
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct CompileError {
     line: usize,
+    column: usize,
+    span: Range<usize>,
     error: CompileErrorKind
 }
 
 impl CompileError {
 
+    fn new(module: &parse::MmgxModule, span: Range<usize>, error: CompileErrorKind) -> Self
+    {
+        let (line, column) = module.position(span.start);
+        Self { line, column, span, error }
+    }
+
+    /// Line of the failing statement, counted from 0
+    pub fn line(&self) -> usize
+    {
+        self.line
+    }
+
+    /// Byte offset of the failing statement within its line
+    pub fn column(&self) -> usize
+    {
+        self.column
+    }
+
+    /// Byte range of the failing statement
+    pub fn span(&self) -> Range<usize>
+    {
+        self.span.clone()
+    }
+
+    pub fn kind(&self) -> &CompileErrorKind
+    {
+        &self.error
+    }
+
     fn fmt_err(f: &mut std::fmt::Formatter, error: &CompileErrorKind, indent: usize) -> std::fmt::Result
     {
         for _ in 0..indent {
@@ -34,28 +92,83 @@ impl CompileError {
             CompileErrorKind::TemplateParameterOutOfRange((got, max)) => writeln!(f, "Tried indexing template Parameter {} but only {} are specified!", got, max),
             CompileErrorKind::TemplateNotFound(name) => writeln!(f, "Template not found {}", name),
             CompileErrorKind::ModuleNotFound(name) => writeln!(f, "Module not found {}", name),
+            CompileErrorKind::UnknownVariable(name) => writeln!(f, "Unknown variable {} in expression", name),
+            CompileErrorKind::DivisionByZero => writeln!(f, "Division by zero in expression"),
+            CompileErrorKind::ArithmeticOverflow => writeln!(f, "Arithmetic overflow in expression"),
+            CompileErrorKind::Multiple(errors) => {
+                writeln!(f, "{} errors:", errors.len())?;
+                for error in errors {
+                    Self::fmt_err(f, error, indent+1)?;
+                }
+                Ok(())
+            },
+            CompileErrorKind::InvalidStep(step) => writeln!(f, "Step of a range has to be positive, got {}", step),
+            CompileErrorKind::NegativeTemplateValue(val) => writeln!(f, "Template parameter {} can not be part of a macro name", val),
+            CompileErrorKind::ImportNotFound(name) => writeln!(f, "Imported file \"{}\" not found", name),
+            CompileErrorKind::ImportCycle(cycle) => writeln!(f, "Import cycle {}", cycle.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join(" -> ")),
+            CompileErrorKind::DuplicateConstant(name) => writeln!(f, "Constant {} is defined twice", name),
+            CompileErrorKind::TemplateNotImplemented((name, values)) => writeln!(f, "{}<{}> is not covered by any @impl", name,
+                values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")),
         }
     }
     fn fmt(&self, f: &mut std::fmt::Formatter, path: &Path) -> std::fmt::Result {
-        write!(f, "error at {}:{} ", path.to_str().unwrap(), self.line)?;
+        write!(f, "error at {} ", location(path, self.line, self.column))?;
         Self::fmt_err(f, &self.error, 0)
     }
 }
 
-enum MmgxErrorKind {
+impl Display for CompileErrorKind {
+
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        CompileError::fmt_err(f, self, 0)
+    }
+}
+
+// 2 is taken by clap for invalid arguments
+pub const EXIT_COMPILE_ERROR: u8 = 1;
+pub const EXIT_PARSE_ERROR: u8 = 3;
+pub const EXIT_IO_ERROR: u8 = 4;
+
+#[derive(Debug)]
+pub enum MmgxErrorKind {
     ParseError(parse::ParseError),
     FileReadError(String),
     FileOpenError(String),
     FileWriteError(String),
-    CompileError(CompileError)
+    OverwriteInput(PathBuf),
+    DuplicateOutput,
+    CompileError(CompileError),
+    ExpandError(String)
 }
 
-pub struct MmgxError {
+#[derive(Debug)]
+pub struct Diagnostic {
     path: PathBuf,
     error: MmgxErrorKind
 }
 
-impl Display for MmgxError {
+impl Diagnostic {
+
+    /// File the error occurred in
+    pub fn path(&self) -> &Path
+    {
+        &self.path
+    }
+
+    pub fn kind(&self) -> &MmgxErrorKind
+    {
+        &self.error
+    }
+}
+
+/// Every error of a run, each with the file it occurred in
+#[derive(Debug)]
+pub struct MmgxError {
+    diagnostics: Vec<Diagnostic>
+}
+
+impl Display for Diagnostic {
 
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
     {
@@ -67,40 +180,114 @@ impl Display for MmgxError {
             MmgxErrorKind::FileReadError(reason) => write!(f, "Could not read file '{}': {}", self.path.to_str().unwrap(), reason),
             MmgxErrorKind::FileOpenError(reason) => write!(f, "Could not open file '{}': {}", self.path.to_str().unwrap(), reason),
             MmgxErrorKind::FileWriteError(reason) => write!(f, "Could not write file '{}': {}", self.path.to_str().unwrap(), reason),
+            MmgxErrorKind::OverwriteInput(input) => write!(f, "Refusing to write '{}', it is the input file '{}'", self.path.to_str().unwrap(), input.to_str().unwrap()),
+            MmgxErrorKind::DuplicateOutput => write!(f, "Refusing to write '{}' more than once, several outputs map to it", self.path.to_str().unwrap()),
             MmgxErrorKind::CompileError(cmp) => {
                 writeln!(f, "Compiling failed!")?;
                 cmp.fmt(f, &self.path)
+            },
+            MmgxErrorKind::ExpandError(reason) => {
+                writeln!(f, "Expanding failed!")?;
+                writeln!(f, "error in {}: {}", self.path.to_str().unwrap(), reason)
             }
         }
     }
 
 }
 
+impl Display for MmgxError {
+
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        for diagnostic in self.diagnostics.iter() {
+            writeln!(f, "{}", diagnostic)?;
+        }
+        write!(f, "{} error{} found", self.diagnostics.len(), if self.diagnostics.len()==1 {""} else {"s"})
+    }
+
+}
+
+impl std::error::Error for MmgxError {}
+
 impl MmgxError {
 
-    pub fn file_read_error(path: &PathBuf, error: std::io::Error) -> Self
+    fn single(path: &Path, error: MmgxErrorKind) -> Self
+    {
+        Self{diagnostics: vec![Diagnostic{path: path.into(), error}]}
+    }
+
+    pub(crate) fn new() -> Self
+    {
+        Self{diagnostics: Vec::new()}
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic]
+    {
+        &self.diagnostics
+    }
+
+    pub(crate) fn append(&mut self, mut other: MmgxError)
+    {
+        self.diagnostics.append(&mut other.diagnostics);
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.diagnostics.is_empty()
+    }
+
+    /// Exit status of the command line tool, I/O problems outrank parse errors, which outrank compile errors
+    pub fn exit_code(&self) -> u8
+    {
+        self.diagnostics.iter()
+            .map(|d| match d.error {
+                MmgxErrorKind::FileReadError(_) | MmgxErrorKind::FileOpenError(_) | MmgxErrorKind::FileWriteError(_) | MmgxErrorKind::OverwriteInput(_) |
+                MmgxErrorKind::DuplicateOutput => EXIT_IO_ERROR,
+                MmgxErrorKind::ParseError(_) => EXIT_PARSE_ERROR,
+                MmgxErrorKind::CompileError(_) | MmgxErrorKind::ExpandError(_) => EXIT_COMPILE_ERROR
+            })
+            .max()
+            .unwrap_or(EXIT_COMPILE_ERROR)
+    }
+
+    pub(crate) fn file_read_error(path: &Path, error: std::io::Error) -> Self
+    {
+        Self::single(path, MmgxErrorKind::FileReadError(error.to_string()))
+    }
+
+    pub(crate) fn file_open_error(path: &Path, error: std::io::Error) -> Self
+    {
+        Self::single(path, MmgxErrorKind::FileOpenError(error.to_string()))
+    }
+
+    pub(crate) fn file_write_error(path: &Path, error: std::io::Error) -> Self
+    {
+        Self::single(path, MmgxErrorKind::FileWriteError(error.to_string()))
+    }
+
+    pub(crate) fn overwrite_input(path: &Path, input: &Path) -> Self
     {
-        Self{path: path.into(), error: MmgxErrorKind::FileReadError(error.to_string())}
+        Self::single(path, MmgxErrorKind::OverwriteInput(input.into()))
     }
 
-    pub fn file_open_error(path: &PathBuf, error: std::io::Error) -> Self
+    pub(crate) fn duplicate_output(path: &Path) -> Self
     {
-        Self{path: path.into(), error: MmgxErrorKind::FileOpenError(error.to_string())}
+        Self::single(path, MmgxErrorKind::DuplicateOutput)
     }
 
-    pub fn file_write_error(path: &PathBuf, error: std::io::Error) -> Self
+    pub(crate) fn parse_error(path: &Path, error: parse::ParseError) -> Self
     {
-        Self{path: path.into(), error: MmgxErrorKind::FileWriteError(error.to_string())}
+        Self::single(path, MmgxErrorKind::ParseError(error))
     }
 
-    pub fn parse_error(path: &PathBuf, error: parse::ParseError) -> Self
+    pub(crate) fn compile_error(path: &Path, error: CompileError) -> Self
     {
-        Self{path: path.into(), error: MmgxErrorKind::ParseError(error)}
+        Self::single(path, MmgxErrorKind::CompileError(error))
     }
 
-    pub fn compile_error(path: &PathBuf, error: CompileError) -> Self
+    pub(crate) fn expand_error(path: &Path, reason: String) -> Self
     {
-        Self {path: path.into(), error: MmgxErrorKind::CompileError(error)}
+        Self::single(path, MmgxErrorKind::ExpandError(reason))
     }
 }
 
@@ -115,32 +302,192 @@ trait Compile{
     fn name(&self) -> &String;
 }
 
+// names visible to an expression, later entries shadow earlier ones
+type Scope<'a> = Vec<(&'a str, i64)>;
+
+// what the statements of a module are compiled with
+struct Env<'a> {
+    constants: Scope<'a>,
+    // values of each `@impl`, evaluated once per module, None if they fail to
+    impls: Vec<(&'a parse::CommandImpl, Option<Vec<Vec<i64>>>)>
+}
+
+impl parse::Expr {
+    fn eval(&self, scope: &Scope) -> Result<i64, CompileErrorKind>
+    {
+        match self {
+            parse::Expr::Number(num) => Ok(*num),
+            parse::Expr::Variable(name) => scope.iter().rev()
+                .find(|e| e.0 == name)
+                .map(|e| e.1)
+                .ok_or_else(|| CompileErrorKind::UnknownVariable(name.clone())),
+            parse::Expr::Unary(op, expr) => {
+                let val = expr.eval(scope)?;
+                match op {
+                    parse::UnaryOp::Negate => val.checked_neg().ok_or(CompileErrorKind::ArithmeticOverflow),
+                    parse::UnaryOp::Not => Ok((val == 0) as i64)
+                }
+            },
+            parse::Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(scope)?;
+
+                // short circuit like C does
+                match op {
+                    parse::BinaryOp::And if lhs == 0 => {return Ok(0);},
+                    parse::BinaryOp::Or if lhs != 0 => {return Ok(1);},
+                    _ => {}
+                }
+
+                let rhs = rhs.eval(scope)?;
+                match op {
+                    parse::BinaryOp::Add => lhs.checked_add(rhs).ok_or(CompileErrorKind::ArithmeticOverflow),
+                    parse::BinaryOp::Sub => lhs.checked_sub(rhs).ok_or(CompileErrorKind::ArithmeticOverflow),
+                    parse::BinaryOp::Mul => lhs.checked_mul(rhs).ok_or(CompileErrorKind::ArithmeticOverflow),
+                    parse::BinaryOp::Div | parse::BinaryOp::Rem if rhs == 0 => Err(CompileErrorKind::DivisionByZero),
+                    parse::BinaryOp::Div => lhs.checked_div(rhs).ok_or(CompileErrorKind::ArithmeticOverflow),
+                    parse::BinaryOp::Rem => lhs.checked_rem(rhs).ok_or(CompileErrorKind::ArithmeticOverflow),
+                    parse::BinaryOp::Less => Ok((lhs < rhs) as i64),
+                    parse::BinaryOp::LessEqual => Ok((lhs <= rhs) as i64),
+                    parse::BinaryOp::Greater => Ok((lhs > rhs) as i64),
+                    parse::BinaryOp::GreaterEqual => Ok((lhs >= rhs) as i64),
+                    parse::BinaryOp::Equal => Ok((lhs == rhs) as i64),
+                    parse::BinaryOp::NotEqual => Ok((lhs != rhs) as i64),
+                    parse::BinaryOp::And | parse::BinaryOp::Or => Ok((rhs != 0) as i64)
+                }
+            }
+        }
+    }
+}
+
+impl parse::ValueSet {
+    fn values(&self, scope: &Scope) -> Result<Vec<i64>, CompileErrorKind>
+    {
+        match self {
+            parse::ValueSet::List(list) => list.iter().map(|e| e.eval(scope)).collect(),
+            parse::ValueSet::Range { start, end, inclusive, step } => {
+                let start = start.eval(scope)?;
+                let end = end.eval(scope)?;
+                let step = match step {
+                    Some(step) => step.eval(scope)?,
+                    None => 1
+                };
+
+                if step <= 0 {
+                    return Err(CompileErrorKind::InvalidStep(step));
+                }
+
+                // ranges count down if the end lies below the start
+                let mut res = Vec::new();
+                let mut val = Some(start);
+                while let Some(v) = val {
+                    let past = if start <= end {v > end} else {v < end};
+                    if past || (v == end && !inclusive) {
+                        break;
+                    }
+                    res.push(v);
+                    val = if start <= end {v.checked_add(step)} else {v.checked_sub(step)};
+                }
+                Ok(res)
+            }
+        }
+    }
+}
+
 impl parse::Object {
     fn get_name(&self, prefix_name: &String, prefix: bool) -> String
     {
         format!("{}{}_{}", if prefix{"__"}else{""}, prefix_name, self.name)
     }
+    fn scope<'a>(constants: &Scope<'a>, params: Option<(&'a Vec<i64>, &'a Vec<parse::TemplateParameter>)>) -> Scope<'a>
+    {
+        let mut scope = constants.clone();
+        if let Some((val, map)) = params {
+            scope.extend(map.iter()
+                .filter_map(|p| match p {
+                    parse::TemplateParameter::Param(name) => Some(name.as_str()),
+                    _ => None
+                })
+                .zip(val.iter().copied()));
+        }
+        scope
+    }
+
+    // true if an @impl of the template or an object with the mangled name provides the call
+    fn implemented(parent: &parse::MmgxModule, env: &Env, name: &String, values: &[i64]) -> Result<bool, CompileErrorKind>
+    {
+        let template = parent.body.iter().find_map(|e| match e {
+            parse::Statement::Template(temp) if &temp.obj.name==name => Some(temp),
+            _ => None
+        });
+
+        let template = match template {
+            Some(template) => template,
+            None => {return Ok(true);}
+        };
+
+        let mangled = values.iter().fold(name.clone(), |acc, v| format!("{}_{}", acc, v));
+        if parent.body.iter().any(|e| matches!(e, parse::Statement::Object(obj) if obj.name==mangled)) {
+            return Ok(true);
+        }
+
+        if values.len() != template.params.len() {
+            return Ok(false);
+        }
+
+        let mut args = Vec::new();
+        for (p, val) in template.params.iter().zip(values) {
+            match p {
+                parse::TemplateParameter::Param(_) => args.push(*val),
+                parse::TemplateParameter::Reference(i) => if values[*i] != *val {
+                    return Ok(false);
+                }
+            }
+        }
+
+        for (cmd_impl, sets) in env.impls.iter() {
+            if &cmd_impl.template!=name || cmd_impl.params.len()!=args.len() {
+                continue;
+            }
+            // a broken @impl is reported on its own line
+            let sets = match sets {
+                Some(sets) => sets,
+                None => {return Ok(true);}
+            };
+            let mut covered = sets.iter().zip(&args).all(|(set, val)| set.contains(val));
+            if let (true, Some(condition)) = (covered, &cmd_impl.condition) {
+                covered = condition.eval(&Self::scope(&env.constants, Some((&args, &template.params))))? != 0;
+            }
+            if covered {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // `Name<...>` calls a template of the module or passes on known names only,
+    // anything else is left to C like the comparisons in `(i<n-1 && n>0)`
+    fn calls_template(parent: &parse::MmgxModule, call: &parse::TemplateCall, scope: &Scope) -> bool
+    {
+        parent.body.iter().any(|e| matches!(e, parse::Statement::Template(temp) if temp.obj.name==call.name))
+            || call.args.iter().all(|arg| match arg {
+                parse::TemplateArgument::Macro(_) => true,
+                parse::TemplateArgument::Expr(parse::Expr::Variable(name)) => scope.iter().any(|e| e.0==name),
+                parse::TemplateArgument::Expr(_) => false
+            })
+    }
+
     fn resolve(name: &String, parent: &parse::MmgxModule, prefix_name: &String) -> String
     {
         for e in parent.body.iter() {
             match e {
-                parse::Statement::Template(temp) => {
-                    if &temp.obj.name==name {
-                        return temp.obj.get_name(prefix_name, true);
-                    }
+                parse::Statement::Template(temp) if &temp.obj.name==name => {
+                    return temp.obj.get_name(prefix_name, true);
                 },
-                parse::Statement::Object(obj) => {
-                    if &obj.name==name {
-                        return obj.get_name(prefix_name, true);
-                    }
+                parse::Statement::Object(obj) if &obj.name==name => {
+                    return obj.get_name(prefix_name, true);
                 },
-                parse::Statement::Command(cmd) => match cmd {
-                    parse::Command::Export(obj) => {
-                        if &obj.name==name {
-                            return obj.get_name(prefix_name, false);
-                        }
-                    },
-                    _=>{}
+                parse::Statement::Command(parse::Command::Export(obj)) if &obj.name==name => {
+                    return obj.get_name(prefix_name, false);
                 },
                 _ => {}
             }
@@ -151,13 +498,13 @@ impl parse::Object {
 
 impl Compile for parse::Object
 {
-    type Args<'a> = (&'a parse::MmgxModule, &'a String, bool, &'a Vec<&'a Rc<parse::MmgxModule>>, Option<(&'a Vec<usize>, &'a Vec<parse::TemplateParameter>)>);
+    type Args<'a> = (&'a parse::MmgxModule, &'a String, bool, &'a Vec<&'a Rc<parse::MmgxModule>>, &'a Env<'a>, Option<(&'a Vec<i64>, &'a Vec<parse::TemplateParameter>)>);
     fn name(&self) -> &String
     {
         &self.name
     }
 
-    fn internal_compile<'a>(&self, (parent, prefix_name, prefix, external, params): Self::Args<'a>) -> Result<String, CompileErrorKind>
+    fn internal_compile<'a>(&self, (parent, prefix_name, prefix, external, env, params): Self::Args<'a>) -> Result<String, CompileErrorKind>
     {
         let mut res = format!("#define {}", self.get_name(prefix_name, prefix));
 
@@ -181,15 +528,12 @@ impl Compile for parse::Object
                         if let Some(parse::Statement::Object(obj)) = module.body.iter()
                             .find(|e| match e {
                                 parse::Statement::Object(obj) => obj.name==ext.object,
-                                parse::Statement::Command(cmd) => match cmd {
-                                    parse::Command::Export(obj) => obj.name==ext.object,
-                                    _=> false
-                                }
+                                parse::Statement::Command(parse::Command::Export(obj)) => obj.name==ext.object,
                                 _ => false
                         }) {
                             if ext.implement {
                                 let prefix = format!("__{}_{}_IMPL_{}", prefix_name, self.name, ext.module);
-                                res = obj.compile((parent, &prefix, false, external, None))? + res.as_str() + prefix.as_str();
+                                res = obj.compile((parent, &prefix, false, external, env, None))? + res.as_str() + prefix.as_str();
                             } else {
                                 res+= format!("__{}_{}", ext.module, ext.object).as_str();
                             }
@@ -214,29 +558,40 @@ impl Compile for parse::Object
                         return Err(CompileErrorKind::NoTemplateParameters);
                     }
                 },
+                parse::BodyStatement::Eval(expr) => {
+                    res+=expr.eval(&Self::scope(&env.constants, params))?.to_string().as_str();
+                },
                 parse::BodyStatement::OptDependency(string) => {
                     res += Self::resolve(string, parent, prefix_name).as_str();
                 },
+                parse::BodyStatement::TemplateCall(call) if !Self::calls_template(parent, call, &Self::scope(&env.constants, params)) => {
+                    res += call.text.as_str();
+                },
                 parse::BodyStatement::TemplateCall(call) => {
+                    let scope = Self::scope(&env.constants, params);
+                    let mut values = Vec::new();
+                    let mut pasted = false;
+
                     res+= Self::resolve(&call.name, parent, prefix_name).as_str();
-                    for v in &call.args {
-                        let mut found = false;
-                        if let Some(params) = params {
-                            for p in params.1.iter().enumerate() {
-                                if match p.1 {
-                                    parse::TemplateParameter::Param(string) => string==v,
-                                    _=> false
-                                } {
-                                    res+="_";
-                                    res+=params.0[p.0].to_string().as_str();
-                                    found=true;
-                                }
+                    for arg in &call.args {
+                        match arg {
+                            parse::TemplateArgument::Expr(expr) => {
+                                let val = expr.eval(&scope)?;
+                                values.push(val);
+                                res+="_";
+                                res+=val.to_string().as_str();
+                            },
+                            parse::TemplateArgument::Macro(name) => {
+                                pasted = true;
+                                res+="##_##";
+                                res+=name.as_str();
                             }
                         }
-                        if !found {
-                            res+="##_##";
-                            res+=v.as_str();
-                        }
+                    }
+
+                    // pasted names are only known to the C preprocessor
+                    if !pasted && !Self::implemented(parent, env, &call.name, &values)? {
+                        return Err(CompileErrorKind::TemplateNotImplemented((call.name.clone(), values)));
                     }
                 }
             }
@@ -247,18 +602,152 @@ impl Compile for parse::Object
 
 impl parse::MmgxModule {
 
-    fn recursive_impl(res: &mut String, template: &parse::Template, parent: &parse::MmgxModule, prefix_name: &String, prefix: bool, external: &Vec<&Rc<parse::MmgxModule>>, ranges: &Vec<RangeInclusive<usize>>, param: &mut Vec<usize>, idx: usize) -> Result<(), CompileErrorKind>
+    // constants a module defines, evaluated with the constants of the modules it uses
+    fn constants<'a>(&'a self, modules: &'a Vec<Rc<parse::MmgxModule>>, visiting: &mut Vec<&'a str>, exported: bool) -> Result<Scope<'a>, CompileErrorKind>
+    {
+        let mut scope = Scope::new();
+        let mut own = Scope::new();
+
+        visiting.push(&self.name);
+        for statement in self.body.iter() {
+            match statement {
+                parse::Statement::Command(parse::Command::Use(name)) => {
+                    // missing modules are reported while compiling, cyclic ones provide no constants
+                    if let Some(module) = modules.iter().find(|e| &e.name == name && !visiting.contains(&e.name.as_str())) {
+                        scope.extend(module.constants(modules, visiting, true)
+                            .map_err(|err| CompileErrorKind::ErrorWhileCompiling((module.name.clone(), Box::new(err))))?);
+                    }
+                },
+                parse::Statement::Command(parse::Command::Let(cmd_let)) => {
+                    // constants of used modules may be shadowed, the module's own ones not
+                    if own.iter().any(|e| e.0 == cmd_let.name) {
+                        return Err(CompileErrorKind::DuplicateConstant(cmd_let.name.clone()));
+                    }
+                    let val = cmd_let.value.eval(&scope)
+                        .map_err(|err| CompileErrorKind::ErrorWhileCompiling((cmd_let.name.clone(), Box::new(err))))?;
+                    scope.push((&cmd_let.name, val));
+                    own.push((&cmd_let.name, val));
+                },
+                _ => {}
+            }
+        }
+        visiting.pop();
+
+        Ok(if exported {own} else {scope})
+    }
+
+    fn compile_statement<'a>(&'a self, statement: &'a parse::Statement, modules: &'a [Rc<parse::MmgxModule>], env: &'a Env<'a>, externs: &mut Vec<&'a Rc<parse::MmgxModule>>, templates: &mut Vec<&'a parse::Template>, res: &mut String) -> Result<(), CompileErrorKind>
     {
-        if idx < ranges.len() {
-            for i in ranges.get(idx).unwrap().clone() {
-                param[idx] = i;
-                Self::recursive_impl(res, template, parent, prefix_name, prefix, external, ranges, param, idx+1)?;
+        match statement {
+            parse::Statement::Command(cmd) => {
+                match cmd {
+                    parse::Command::Use(name) => {
+                        if let Some(module) = modules.iter().find(|e| &e.name == name) {
+                            externs.push(module);
+                        } else {
+                            return Err(CompileErrorKind::ModuleNotFound(name.clone()));
+                        }
+                    },
+                    parse::Command::Impl(cmd_impl) => {
+                        if let Some(template) = templates.iter().find(|e| e.obj.name == cmd_impl.template) {
+
+                            let imp_len = cmd_impl.params.len();
+                            let tem_len = template.params.iter().filter(|e| matches!(e, parse::TemplateParameter::Param(_))).count();
+
+                            if imp_len == tem_len {
+                                let sets = match env.impls.iter().find(|e| std::ptr::eq(e.0, cmd_impl)) {
+                                    Some((_, Some(sets))) => sets,
+                                    // evaluated again only to report why they failed
+                                    _ => {
+                                        for set in cmd_impl.params.iter() {
+                                            set.values(&env.constants)?;
+                                        }
+                                        return Ok(());
+                                    }
+                                };
+                                let mut args = vec![0; imp_len];
+                                Self::recursive_impl(res, template, (self, &self.name, true, externs, env, None), sets, cmd_impl.condition.as_ref(), &mut args, 0)?;
+                            } else {
+                                return Err(CompileErrorKind::TemplateParameterOutOfRange((imp_len, tem_len)));
+                            }
+                        } else {
+                            return Err(CompileErrorKind::TemplateNotFound(cmd_impl.template.clone()))
+                        }
+                    },
+                    parse::Command::Export(obj) => {
+                        res.push_str(obj.compile((self, &self.name, false, externs, env, None))?.as_str())
+                    },
+                    // resolved before compiling, tests are run by `test_files`
+                    parse::Command::Let(_) | parse::Command::Import(_) | parse::Command::Test(_) => {}
+                }
+            },
+            parse::Statement::Object(obj) => {
+                res.push_str(obj.compile((self, &self.name, true, externs, env, None))?.as_str());
+            },
+            parse::Statement::Template(temp) => {
+                templates.push(temp);
+            },
+            parse::Statement::Comment(string) => *res+=string.as_str(),
+        }
+        Ok(())
+    }
+
+    fn recursive_impl(res: &mut String, template: &parse::Template, args: <parse::Object as Compile>::Args<'_>, sets: &Vec<Vec<i64>>, condition: Option<&parse::Expr>, param: &mut Vec<i64>, idx: usize) -> Result<(), CompileErrorKind>
+    {
+        let (parent, prefix_name, prefix, external, env, _) = args;
+
+        if idx < sets.len() {
+            for i in sets[idx].iter() {
+                if *i < 0 {
+                    return Err(CompileErrorKind::NegativeTemplateValue(*i));
+                }
+                param[idx] = *i;
+                Self::recursive_impl(res, template, args, sets, condition, param, idx+1)?;
             }
         } else {
-            res.push_str(template.obj.compile((parent, prefix_name, prefix, external, Some((param, &template.params))))?.as_str());
+            if let Some(condition) = condition {
+                if condition.eval(&parse::Object::scope(&env.constants, Some((param, &template.params))))? == 0 {
+                    return Ok(());
+                }
+            }
+            res.push_str(template.obj.compile((parent, prefix_name, prefix, external, env, Some((param, &template.params))))?.as_str());
         };
         Ok(())
     }
+
+    // the output of every statement, marked if it is exported.
+    // A failing statement is reported with its source range, the rest of the module still gets compiled.
+    pub(crate) fn compile_parts(&self, modules: &Vec<Rc<parse::MmgxModule>>, errors: &mut Vec<(Range<usize>, CompileErrorKind)>) -> Vec<(bool, String)>
+    {
+        let mut parts = Vec::new();
+        let mut externs = Vec::new();
+        let mut templates: Vec<&parse::Template> = Vec::new();
+        let constants = match self.constants(modules, &mut Vec::new(), false) {
+            Ok(constants) => constants,
+            Err(err) => {
+                errors.push((self.span.clone(), err));
+                return parts;
+            }
+        };
+        let impls = self.body.iter()
+            .filter_map(|statement| match statement {
+                parse::Statement::Command(parse::Command::Impl(cmd_impl)) => Some(cmd_impl),
+                _ => None
+            })
+            .map(|cmd_impl| (cmd_impl, cmd_impl.params.iter().map(|set| set.values(&constants).ok()).collect()))
+            .collect();
+        let env = Env { constants, impls };
+
+        for (statement, span) in self.body.iter().zip(self.spans.iter()) {
+            let mut res = String::new();
+            if let Err(err) = self.compile_statement(statement, modules, &env, &mut externs, &mut templates, &mut res) {
+                errors.push((span.clone(), err));
+            }
+            res+="\n";
+            parts.push((matches!(statement, parse::Statement::Command(parse::Command::Export(_))), res));
+        };
+        parts
+    }
 }
 
 impl Compile for parse::MmgxModule {
@@ -272,64 +761,69 @@ impl Compile for parse::MmgxModule {
 
     fn internal_compile<'a>(&self, modules: Self::Args<'a>) -> Result<String, CompileErrorKind>
     {
-        let mut res = String::new();
-        let mut externs = Vec::new();
-        let mut templates: Vec<&parse::Template> = Vec::new();
+        let mut errors = Vec::new();
+        let parts = self.compile_parts(modules, &mut errors);
+        if errors.is_empty() {
+            Ok(parts.into_iter().map(|part| part.1).collect())
+        } else {
+            Err(CompileErrorKind::Multiple(errors.into_iter().map(|e| e.1).collect()))
+        }
+    }
+}
 
-        for statement in self.body.iter() {
-            match statement {
-                parse::Statement::Command(cmd) => {
-                    match cmd {
-                        parse::Command::Use(name) => {
-                            if let Some(module) = modules.iter().find(|e| &e.name == name) {
-                                externs.push(module);
-                            } else {
-                                return Err(CompileErrorKind::ModuleNotFound(name.clone()));
-                            }
-                        },
-                        parse::Command::Impl(cmd_impl) => {
-                            if let Some(template) = templates.iter().find(|e| e.obj.name == cmd_impl.template) {
-
-                                let imp_len = cmd_impl.params.len();
-                                let tem_len = template.params.iter().filter(|e| matches!(e, parse::TemplateParameter::Param(_))).count();
-
-                                if imp_len == tem_len {
-                                    let mut args = Vec::new();
-                                    args.resize(imp_len, 0);
-                                    Self::recursive_impl(&mut res, template, &self, &self.name, true, &externs, &cmd_impl.params, &mut args, 0)?;
-                                } else {
-                                    return Err(CompileErrorKind::TemplateParameterOutOfRange((imp_len, tem_len)));
-                                }
-                            } else {
-                                return Err(CompileErrorKind::TemplateNotFound(cmd_impl.template.clone()))
-                            }
-                        },
-                        parse::Command::Export(obj) => {
-                            res.push_str(obj.compile((&self, &self.name, false, &externs, None))?.as_str())
-                        }
-                    }
-                },
-                parse::Statement::Object(obj) => {
-                    res.push_str(obj.compile((&self, &self.name, true, &externs, None))?.as_str());
-                },
-                parse::Statement::Template(temp) => {
-                    templates.push(temp);
-                },
-                parse::Statement::Comment(string) => res+=string.as_str(),
-            }
-            res+="\n";
-        };
-        Ok(res)
+// The C code of a file and, in header mode, the module output apart from it
+pub(crate) struct CompiledFile {
+    source: String,
+    // marked if exported
+    definitions: Vec<(bool, String)>
+}
+
+// `#line` directive making the following line count as `line` (from 0) of `path`
+fn line_directive(path: &Path, line: usize) -> String
+{
+    let name = input_name(path).to_string_lossy().replace('\\', "\\\\").replace('"', "\\\"");
+    format!("#line {} \"{}\"\n", line+1, name)
+}
+
+// every define of the output is traced back to the definition it comes from
+fn with_line_directives(output: &str, directive: &str) -> String
+{
+    let mut res = String::new();
+    let mut continued = false;
+    for line in output.split_inclusive('\n') {
+        if !continued && line.starts_with("#define") {
+            res += directive;
+        }
+        continued = line.ends_with("\\\n");
+        res += line;
+    }
+    res
+}
+
+// Line of the definition the output of a statement comes from
+fn statement_line(module: &parse::MmgxModule, statement: &parse::Statement) -> Option<usize>
+{
+    match statement {
+        parse::Statement::Object(obj) | parse::Statement::Command(parse::Command::Export(obj)) => Some(obj.line),
+        parse::Statement::Command(parse::Command::Impl(cmd_impl)) => module.body.iter().find_map(|e| match e {
+            parse::Statement::Template(temp) if temp.obj.name == cmd_impl.template => Some(temp.obj.line),
+            _ => None
+        }),
+        _ => None
     }
 }
 
-fn compile(files: Vec<(File, &PathBuf, Vec<parse::Section>)>) -> Result<(), MmgxError>
+// Modules of `library` can be used but produce no output.
+// The first module of each file is preceded by its entry of `includes`.
+// With `headers`, module output is kept apart for a header, which the entry of `includes` should name.
+// With `line_directives`, C code and defines are preceded by `#line` directives pointing into the source.
+pub(crate) fn compile(files: &[(&PathBuf, Vec<parse::Section>)], library: &[(PathBuf, Vec<parse::Section>)], includes: &[String], headers: bool, line_directives: bool) -> Result<Vec<CompiledFile>, MmgxError>
 {
     let mut modules: Vec<Rc<parse::MmgxModule>> = Vec::new();
 
     // collect all Modules
-    for file in files.iter() {
-            modules.append(&mut file.2.iter()
+    for sections in files.iter().map(|file| &file.1).chain(library.iter().map(|file| &file.1)) {
+            modules.append(&mut sections.iter()
                 .filter_map(|sec| match sec {
                     parse::Section::MmgxModule(module) => Some(module.clone()),
                     _ => None
@@ -337,32 +831,1344 @@ fn compile(files: Vec<(File, &PathBuf, Vec<parse::Section>)>) -> Result<(), Mmgx
                 .collect());
     };
 
-    for mut file in files {
-        for section in file.2.iter() {
-            let res = match section {
-                    parse::Section::CSource(string) => string.clone(),
-                    parse::Section::MmgxModule(module) => {
-                        module.compile(&modules)
-                        .map_err(|ek| MmgxError::compile_error(file.1, CompileError { line: module.line, error: ek}))?
+    let mut errors = MmgxError::new();
+    let mut outputs = Vec::new();
 
+    for (i, file) in files.iter().enumerate() {
+        let mut output = CompiledFile { source: String::new(), definitions: Vec::new() };
+        let mut included = false;
+        // C code continues on the line the previous module ends on
+        let mut line = 0;
+        for section in file.1.iter() {
+            match section {
+                parse::Section::CSource(string, _) => {
+                    if line_directives {
+                        if !output.source.is_empty() && !output.source.ends_with('\n') {
+                            output.source.push('\n');
+                        }
+                        output.source += &line_directive(file.0, line);
                     }
-                };
-            file.0.write_all(res.as_bytes()).map_err(|err| MmgxError::file_write_error(file.1, err))?;
+                    output.source.push_str(string)
+                },
+                parse::Section::MmgxModule(module) => {
+                    let mut failed = Vec::new();
+                    let mut parts = module.compile_parts(&modules, &mut failed);
+                    if line_directives {
+                        for (part, statement) in parts.iter_mut().zip(module.body.iter()) {
+                            if let Some(line) = statement_line(module, statement) {
+                                part.1 = with_line_directives(&part.1, &line_directive(file.0, line));
+                            }
+                        }
+                    }
+                    line = module.line;
+                    if !included {
+                        output.source.push_str(includes.get(i).map_or("", |include| include.as_str()));
+                        included = true;
+                    }
+                    match headers {
+                        true => output.definitions.extend(parts),
+                        false => output.source.extend(parts.into_iter().map(|part| part.1))
+                    };
+                    for (span, error) in failed {
+                        for error in CompileErrorKind::ErrorWhileCompiling((module.name.clone(), Box::new(error))).flatten() {
+                            errors.append(MmgxError::compile_error(file.0, CompileError::new(module, span.clone(), error)));
+                        }
+                    }
+                }
+            };
         }
+        outputs.push(output);
     };
-    Ok(())
+
+    if errors.is_empty() {
+        Ok(outputs)
+    } else {
+        Err(errors)
+    }
 }
 
-pub fn files(input: Vec<PathBuf>, output: String) -> Result<(), MmgxError>
+// Files contributing modules to each output: its own file and the files of every module reachable through '@use'
+fn dependencies<'a>(files: &'a [(&'a PathBuf, Vec<parse::Section>)], library: &'a [(PathBuf, Vec<parse::Section>)]) -> Vec<Vec<&'a PathBuf>>
 {
+    let modules: Vec<(&parse::MmgxModule, &PathBuf)> = files.iter()
+        .map(|file| (file.0, &file.1))
+        .chain(library.iter().map(|file| (&file.0, &file.1)))
+        .flat_map(|(path, sections)| sections.iter().filter_map(move |sec| match sec {
+            parse::Section::MmgxModule(module) => Some((module.as_ref(), path)),
+            _ => None
+        }))
+        .collect();
 
-    let mut files = Vec::new();
+    files.iter().map(|file| {
+        let mut deps = vec![file.0];
+        let mut pending: Vec<&parse::MmgxModule> = file.1.iter()
+            .filter_map(|sec| match sec {
+                parse::Section::MmgxModule(module) => Some(module.as_ref()),
+                _ => None
+            })
+            .collect();
+        let mut visited: Vec<&str> = pending.iter().map(|module| module.name.as_str()).collect();
 
-    for path in &input {
-        files.push((File::create(path.with_extension(output.as_str())).map_err(|err| MmgxError::file_open_error(path,err))?, path, parse::parse_x_file(path)?));
-        // println!("File {} -> {:?}\n", path.to_str().unwrap(), files.last().unwrap().2);
-    };
+        while let Some(module) = pending.pop() {
+            for statement in module.body.iter() {
+                if let parse::Statement::Command(parse::Command::Use(name)) = statement {
+                    // the same module compile() resolves the name to
+                    if let Some((used, path)) = modules.iter().find(|e| &e.0.name == name) {
+                        if !visited.contains(&used.name.as_str()) {
+                            visited.push(&used.name);
+                            pending.push(used);
+                        }
+                        if !deps.contains(path) {
+                            deps.push(path);
+                        }
+                    }
+                }
+            }
+        }
+        deps
+    }).collect()
+}
 
-    compile(files)
+// A Make rule, escaping the characters Make would split or expand
+fn make_rule(targets: &[&PathBuf], deps: &[&PathBuf]) -> String
+{
+    let escape = |path: &Path| path.to_string_lossy()
+        .replace('$', "$$")
+        .replace('#', "\\#")
+        .replace(' ', "\\ ");
+
+    let mut rule = targets.iter().map(|target| escape(target)).collect::<Vec<_>>().join(" ");
+    rule.push(':');
+    for dep in deps {
+        rule.push_str(" \\\n ");
+        rule.push_str(&escape(dep));
+    }
+    rule.push('\n');
+    rule
 }
 
+// Writes every output next to its target first and only replaces the targets once all of them were written.
+// Targets that already hold the same content are left alone to keep their modification time.
+fn write_outputs(outputs: Vec<(PathBuf, String)>) -> Result<(), MmgxError>
+{
+    let mut errors = duplicate_outputs(outputs.iter().map(|output| output.0.as_path()));
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut written = Vec::new();
+
+    for (i, (path, content)) in outputs.into_iter().enumerate() {
+        if read(&path).is_ok_and(|old| old == content.as_bytes()) {
+            continue;
+        }
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if let Err(err) = create_dir_all(dir) {
+                errors.append(MmgxError::file_open_error(dir, err));
+                continue;
+            }
+        }
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!(".{}.{}.tmp", std::process::id(), i));
+        let tmp = PathBuf::from(tmp);
+
+        let mut file = match File::create(&tmp) {
+            Ok(file) => file,
+            Err(err) => {
+                errors.append(MmgxError::file_open_error(&tmp, err));
+                continue;
+            }
+        };
+        match file.write_all(content.as_bytes()) {
+            Ok(()) => written.push((tmp, path)),
+            Err(err) => {
+                let _ = remove_file(&tmp);
+                errors.append(MmgxError::file_write_error(&tmp, err));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        for (tmp, _) in written {
+            let _ = remove_file(tmp);
+        }
+        return Err(errors);
+    }
+
+    for (tmp, path) in written {
+        if let Err(err) = rename(&tmp, &path) {
+            let _ = remove_file(&tmp);
+            errors.append(MmgxError::file_write_error(&path, err));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Loads the files named by '@import' once each, following their own imports
+struct Importer<'a> {
+    include_dirs: &'a [PathBuf],
+    // without it, only the inputs can be imported
+    filesystem: bool,
+    // files currently being imported, as (canonical, given) path
+    stack: Vec<(PathBuf, PathBuf)>,
+    done: Vec<PathBuf>,
+    library: Vec<(PathBuf, Vec<parse::Section>)>,
+    errors: MmgxError
+}
+
+impl<'a> Importer<'a> {
+
+    fn key(&self, path: &Path) -> PathBuf
+    {
+        match self.filesystem {
+            true => canonicalize(path).unwrap_or_else(|_| path.into()),
+            false => path.into()
+        }
+    }
+
+    // next to the importing file first, then in the include directories
+    fn resolve(&self, from: &Path, name: &str, inputs: &[(PathBuf, &Vec<parse::Section>)]) -> Option<PathBuf>
+    {
+        let dir = from.parent().unwrap_or(Path::new(""));
+        std::iter::once(dir)
+            .chain(self.include_dirs.iter().map(|dir| dir.as_path()))
+            .map(|dir| dir.join(name))
+            .find(|path| inputs.iter().any(|input| input.0 == self.key(path)) || (self.filesystem && path.is_file()))
+    }
+
+    fn visit(&mut self, path: &Path, sections: &[parse::Section], inputs: &[(PathBuf, &Vec<parse::Section>)])
+    {
+        self.stack.push((self.key(path), path.into()));
+
+        let modules = sections.iter().filter_map(|sec| match sec {
+            parse::Section::MmgxModule(module) => Some(module),
+            _ => None
+        });
+        for module in modules {
+            for (statement, span) in module.body.iter().zip(module.spans.iter()) {
+                let name = match statement {
+                    parse::Statement::Command(parse::Command::Import(name)) => name,
+                    _ => {continue;}
+                };
+                let error = |error| MmgxError::compile_error(path, CompileError::new(module, span.clone(), error));
+
+                let target = match self.resolve(path, name, inputs) {
+                    Some(target) => target,
+                    None => {
+                        self.errors.append(error(CompileErrorKind::ImportNotFound(name.clone())));
+                        continue;
+                    }
+                };
+                let key = self.key(&target);
+
+                if let Some(pos) = self.stack.iter().position(|e| e.0 == key) {
+                    let mut cycle: Vec<PathBuf> = self.stack[pos..].iter().map(|e| e.1.clone()).collect();
+                    cycle.push(target);
+                    self.errors.append(error(CompileErrorKind::ImportCycle(cycle)));
+                    continue;
+                }
+                if self.done.contains(&key) {
+                    continue;
+                }
+
+                // files given on the command line are already parsed
+                if let Some(input) = inputs.iter().find(|e| e.0 == key) {
+                    self.visit(&target, input.1, inputs);
+                    continue;
+                }
+                match parse::parse_x_file(&target) {
+                    Ok(sections) => {
+                        self.visit(&target, &sections, inputs);
+                        self.library.push((target, sections));
+                    },
+                    Err(err) => {
+                        self.errors.append(err);
+                        self.done.push(key);
+                    }
+                }
+            }
+        }
+
+        if let Some((key, _)) = self.stack.pop() {
+            self.done.push(key);
+        }
+    }
+}
+
+// The imported files of all inputs, which are not inputs themselves
+pub(crate) fn import(files: &[(&PathBuf, Vec<parse::Section>)], include_dirs: &[PathBuf], filesystem: bool) -> Result<Vec<(PathBuf, Vec<parse::Section>)>, MmgxError>
+{
+    let mut importer = Importer { include_dirs, filesystem, stack: Vec::new(), done: Vec::new(), library: Vec::new(), errors: MmgxError::new() };
+
+    let inputs: Vec<(PathBuf, &Vec<parse::Section>)> = files.iter()
+        .map(|file| (importer.key(file.0), &file.1))
+        .collect();
+
+    for file in files {
+        if !importer.done.contains(&importer.key(file.0)) {
+            importer.visit(file.0, &file.1, &inputs);
+        }
+    }
+
+    if importer.errors.is_empty() {
+        Ok(importer.library)
+    } else {
+        Err(importer.errors)
+    }
+}
+
+/// Compiles parsed documents into one C output each.
+/// `@use` resolves across all of them, `@import` relative to the path of each document.
+pub fn compile_modules(documents: Vec<(PathBuf, Vec<parse::Section>)>) -> Result<Vec<String>, MmgxError>
+{
+    let (paths, sections): (Vec<PathBuf>, Vec<Vec<parse::Section>>) = documents.into_iter().unzip();
+    let files: Vec<(&PathBuf, Vec<parse::Section>)> = paths.iter().zip(sections).collect();
+
+    let library = import(&files, &[], true)?;
+    Ok(compile(&files, &library, &[], false, false)?.into_iter().map(|output| output.source).collect())
+}
+
+/// Compiles documents held in memory, given as (name, source), into one C output each.
+/// Nothing is read from disk, `@import` can only name one of the other documents.
+pub fn compile_sources<'a>(sources: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Vec<String>, MmgxError>
+{
+    let mut documents = Vec::new();
+    let mut errors = MmgxError::new();
+
+    for (name, source) in sources {
+        match parse::parse_source(name, source) {
+            Ok(sections) => documents.push((PathBuf::from(name), sections)),
+            Err(err) => errors.append(err)
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let (paths, sections): (Vec<PathBuf>, Vec<Vec<parse::Section>>) = documents.into_iter().unzip();
+    let files: Vec<(&PathBuf, Vec<parse::Section>)> = paths.iter().zip(sections).collect();
+
+    let library = import(&files, &[], false)?;
+    Ok(compile(&files, &library, &[], false, false)?.into_iter().map(|output| output.source).collect())
+}
+
+/// How `files` names and splits its outputs
+pub struct Options {
+    /// Extension replacing the one of the input file
+    pub extension: String,
+    /// Directory mirroring the layout of the input files below the deepest directory holding them all
+    pub out_dir: Option<PathBuf>,
+    /// Output file of a single input
+    pub output: Option<PathBuf>,
+    /// Print the outputs instead of writing files
+    pub stdout: bool,
+    /// Write a Make rule listing the inputs of each written output
+    pub make_deps: bool,
+    /// File receiving all rules instead of one '.d' file per output
+    pub dep_file: Option<PathBuf>,
+    /// Directories searched for imported files
+    pub include_dirs: Vec<PathBuf>,
+    /// Put the module output into a header next to each output
+    pub emit_header: bool,
+    /// Put the non-exported module output into a second header
+    pub private_header: bool,
+    /// Precede C code and defines with `#line` directives pointing into the input
+    pub line_directives: bool
+}
+
+impl Options {
+    // None stands for stdout, `base` is the directory --out-dir mirrors
+    fn output_path(&self, input: &Path, base: &Path) -> Option<PathBuf>
+    {
+        if self.stdout {
+            return None;
+        }
+        if let Some(output) = &self.output {
+            return Some(output.clone());
+        }
+        // there is no file name to derive an output from
+        if is_stdin(input) {
+            return None;
+        }
+
+        Some(self.derived_path(input, base))
+    }
+
+    // the output `input` gets when it is compiled on its own
+    fn derived_path(&self, input: &Path, base: &Path) -> PathBuf
+    {
+        let path = input.with_extension(self.extension.as_str());
+        match &self.out_dir {
+            Some(dir) => {
+                let path = absolute(&path);
+                match path.strip_prefix(base) {
+                    Ok(relative) => dir.join(relative),
+                    Err(_) => dir.join(path.file_name().unwrap_or_default())
+                }
+            },
+            None => path
+        }
+    }
+
+    // the header and, if split off, the private header of an output
+    fn header_paths(&self, output: &Path) -> Option<(PathBuf, Option<PathBuf>)>
+    {
+        if !self.emit_header {
+            return None;
+        }
+
+        let private = output.file_stem().map(|stem| {
+            let mut name = stem.to_os_string();
+            name.push("_private.h");
+            output.with_file_name(name)
+        });
+        Some((output.with_extension("h"), private.filter(|_| self.private_header)))
+    }
+}
+
+// `path` as seen from `dir`, both relative to the same directory or both absolute
+fn relative_to(path: &Path, dir: &Path) -> PathBuf
+{
+    let path: Vec<Component> = path.components().filter(|c| !matches!(c, Component::CurDir)).collect();
+    let dir: Vec<Component> = dir.components().filter(|c| !matches!(c, Component::CurDir)).collect();
+    let common = path.iter().zip(dir.iter()).take_while(|(a, b)| a == b).count();
+
+    dir[common..].iter().map(|_| Component::ParentDir)
+        .chain(path[common..].iter().copied())
+        .collect()
+}
+
+// An `#include` of `path` from a file in `dir`
+fn include(path: &Path, dir: &Path) -> String
+{
+    format!("#include \"{}\"\n", relative_to(path, dir).to_string_lossy())
+}
+
+// Wraps a header in an include guard named after its file
+fn guard_header(path: &Path, content: &str) -> String
+{
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let mut guard: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() {c.to_ascii_uppercase()} else {'_'})
+        .collect();
+    if !guard.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        guard.insert(0, '_');
+    }
+
+    format!("#ifndef {guard}\n#define {guard}\n\n{content}\n#endif /* {guard} */\n")
+}
+
+/// `-` reads the document from stdin
+pub fn is_stdin(path: &Path) -> bool
+{
+    path.as_os_str() == "-"
+}
+
+// the path diagnostics refer to an input by
+fn input_name(path: &Path) -> PathBuf
+{
+    if is_stdin(path) {PathBuf::from(parse::STDIN_PATH)} else {path.into()}
+}
+
+fn parse_input(path: &PathBuf) -> Result<Vec<parse::Section>, MmgxError>
+{
+    if is_stdin(path) {
+        parse::parse_x_stdin()
+    } else {
+        parse::parse_x_file(path)
+    }
+}
+
+/// The syntax trees of the given files as a JSON array of `{"path": ..., "sections": [...]}`
+pub fn dump_ast(input: Vec<PathBuf>) -> Result<String, MmgxError>
+{
+    let mut documents = Vec::new();
+    let mut errors = MmgxError::new();
+
+    for path in input.iter() {
+        match parse_input(path) {
+            Ok(sections) => documents.push(parse::document_json(&input_name(path), &sections)),
+            Err(err) => errors.append(err)
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(format!("[{}]\n", documents.join(",")))
+    } else {
+        Err(errors)
+    }
+}
+
+fn read_input(path: &PathBuf) -> Result<String, MmgxError>
+{
+    let mut source = String::new();
+    let res = if is_stdin(path) {
+        std::io::stdin().read_to_string(&mut source)
+    } else {
+        File::open(path).and_then(|mut file| file.read_to_string(&mut source))
+    };
+    match res {
+        Ok(_) => Ok(source),
+        Err(err) => Err(MmgxError::file_read_error(&input_name(path), err))
+    }
+}
+
+/// Formats a document held in memory, diagnostics refer to it as `name`
+pub fn format_source(name: &str, source: &str) -> Result<String, MmgxError>
+{
+    let sections = parse::parse_source(name, source)?;
+    Ok(parse::format_document(source, &sections))
+}
+
+/// Formats the given files in place, stdin is formatted to stdout.
+/// With `check` nothing is written. Returns the files that were not formatted.
+pub fn format_files(input: Vec<PathBuf>, check: bool) -> Result<Vec<PathBuf>, MmgxError>
+{
+    let mut outputs = Vec::new();
+    let mut changed = Vec::new();
+    let mut errors = MmgxError::new();
+
+    for path in input {
+        let name = input_name(&path);
+        let parsed = read_input(&path).and_then(|source| Ok((parse::parse_x_source(&name, &source)?, source)));
+        let (sections, source) = match parsed {
+            Ok(res) => res,
+            Err(err) => {
+                errors.append(err);
+                continue;
+            }
+        };
+
+        let formatted = parse::format_document(&source, &sections);
+        if formatted != source {
+            changed.push(name);
+        }
+
+        if is_stdin(&path) {
+            if !check {
+                print!("{}", formatted);
+            }
+        } else {
+            outputs.push((path, formatted));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    if !check {
+        write_outputs(outputs)?;
+    }
+    Ok(changed)
+}
+
+/// Pseudo path of the expression given to `expand_files`, used in diagnostics
+pub const EXPRESSION_PATH: &str = "<expression>";
+
+// The C code the files compile to, imported files included
+fn compiled_source(files: Vec<(&PathBuf, Vec<parse::Section>)>, include_dirs: &[PathBuf]) -> Result<String, MmgxError>
+{
+    let library = import(&files, include_dirs, true)?;
+    let files: Vec<(&PathBuf, Vec<parse::Section>)> = files.into_iter()
+        .chain(library.iter().map(|file| (&file.0, file.1.clone())))
+        .collect();
+    Ok(compile(&files, &[], &[], false, false)?.into_iter().map(|output| output.source).collect())
+}
+
+/// Expands `expression` with the macros the given files compile to, imported files contribute theirs as well.
+/// Returns every replacement made on the way and the final text.
+pub fn expand_files(input: Vec<PathBuf>, include_dirs: &[PathBuf], expression: &str) -> Result<(Vec<Step>, String), MmgxError>
+{
+    let mut files = Vec::new();
+    let mut errors = MmgxError::new();
+
+    for path in input.iter() {
+        match parse_input(path) {
+            Ok(sections) => files.push((path, sections)),
+            Err(err) => errors.append(err)
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let source = compiled_source(files, include_dirs)?;
+    expand_macros(&source, expression).map_err(|err| MmgxError::expand_error(Path::new(EXPRESSION_PATH), err.to_string()))
+}
+
+// `path:line:column` the way C compilers print it, positions are kept counted from 0
+fn location(path: &Path, line: usize, column: usize) -> String
+{
+    format!("{}:{}:{}", path.display(), line+1, column+1)
+}
+
+/// Outcome of one `@test`
+pub struct TestResult {
+    /// File the test is written in
+    pub path: PathBuf,
+    /// Line of the test, counted from 0
+    pub line: usize,
+    /// Byte column of the test, counted from 0
+    pub column: usize,
+    pub expression: String,
+    pub expected: String,
+    /// What the expression expanded to, or why it could not be expanded
+    pub got: Result<String, String>
+}
+
+impl TestResult {
+
+    /// Whether the expansion matches the expectation token by token
+    pub fn passed(&self) -> bool
+    {
+        matches!(&self.got, Ok(got) if same_tokens(got, &self.expected))
+    }
+
+    /// Where the test is written, as `path:line:column` counted from 1
+    pub fn location(&self) -> String
+    {
+        location(&self.path, self.line, self.column)
+    }
+}
+
+// Names of the module in a test expression stand for its macros, like they do in its bodies
+fn resolve_test(expression: &str, module: &parse::MmgxModule) -> String
+{
+    let mut lex = parse::lexer(expression);
+    let mut res = String::new();
+    let mut member = false;
+    while let Some(token) = lex.next() {
+        match token {
+            Ok(parse::CodeToken::Name | parse::CodeToken::Identifier) if !member => res += &parse::Object::resolve(&String::from(lex.slice()), module, &module.name),
+            _ => res += lex.slice()
+        }
+        member = matches!(token, Ok(parse::CodeToken::Dot | parse::CodeToken::ScopeResolution));
+    }
+    res
+}
+
+/// Runs the `@test`s of the given files against the macros they compile to
+pub fn test_files(input: Vec<PathBuf>, include_dirs: &[PathBuf]) -> Result<Vec<TestResult>, MmgxError>
+{
+    let mut files = Vec::new();
+    let mut tests = Vec::new();
+    let mut errors = MmgxError::new();
+
+    for path in input.iter() {
+        let name = input_name(path);
+        let parsed = read_input(path).and_then(|source| parse::parse_x_source(&name, &source));
+        let sections = match parsed {
+            Ok(res) => res,
+            Err(err) => {
+                errors.append(err);
+                continue;
+            }
+        };
+
+        for section in sections.iter() {
+            let module = match section {
+                parse::Section::MmgxModule(module) => module,
+                _ => {continue;}
+            };
+            for (statement, span) in module.body.iter().zip(module.spans.iter()) {
+                if let parse::Statement::Command(parse::Command::Test(test)) = statement {
+                    let (line, column) = module.position(span.start);
+                    tests.push((name.clone(), line, column, test.expression.clone(), resolve_test(&test.expression, module), test.expected.clone()));
+                }
+            }
+        }
+        files.push((path, sections));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let source = compiled_source(files, include_dirs)?;
+    Ok(tests.into_iter().map(|(path, line, column, expression, resolved, expected)| {
+        let got = expand_macros(&source, &resolved).map(|res| res.1).map_err(|err| err.to_string());
+        TestResult { path, line, column, expression, expected, got }
+    }).collect())
+}
+
+// `path` joined to the current directory, normalized
+fn absolute(path: &Path) -> PathBuf
+{
+    normalize(&std::env::current_dir().unwrap_or_default().join(path))
+}
+
+// The deepest directory holding all inputs, the outputs mirror the layout below it
+fn common_dir(inputs: &[PathBuf]) -> PathBuf
+{
+    let mut dirs = inputs.iter()
+        .filter(|path| !is_stdin(path))
+        .map(|path| absolute(path).parent().map(PathBuf::from).unwrap_or_default());
+    let first = dirs.next().unwrap_or_default();
+    dirs.fold(first, |common, dir| common.components().zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool
+{
+    a == b || matches!((canonicalize(a), canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+// `path` without '.' and with '..' applied where it can be, without looking at the file system
+fn normalize(path: &Path) -> PathBuf
+{
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir if matches!(res.components().next_back(), Some(Component::Normal(_))) => {res.pop();},
+            component => res.push(component)
+        }
+    }
+    res
+}
+
+// Outputs named more than once, each reported once. Writing them would silently drop all but one.
+fn duplicate_outputs<'a>(paths: impl IntoIterator<Item = &'a Path>) -> MmgxError
+{
+    let mut errors = MmgxError::new();
+    let mut seen: Vec<(PathBuf, usize)> = Vec::new();
+    for path in paths {
+        let normalized = normalize(path);
+        match seen.iter_mut().find(|e| e.0 == normalized || same_file(&e.0, &normalized)) {
+            Some(entry) => {
+                entry.1+=1;
+                if entry.1 == 2 {
+                    errors.append(MmgxError::duplicate_output(path));
+                }
+            },
+            None => seen.push((normalized, 1))
+        }
+    }
+    errors
+}
+
+/// What a successful run of `files` read and wrote
+pub struct Generated {
+    /// Written files, including headers and dependency files
+    pub outputs: Vec<PathBuf>,
+    /// Every '.x' file read, the inputs as well as imported ones
+    pub sources: Vec<PathBuf>
+}
+
+/// Compiles the given files and writes their outputs, nothing is written if any of them fails
+/// Imported files are not compiled, outputs using their modules `#include` what they generate when compiled on their own
+pub fn files(input: Vec<PathBuf>, options: &Options) -> Result<Generated, MmgxError>
+{
+
+    let mut files = Vec::new();
+
+    let mut errors = MmgxError::new();
+
+    let names: Vec<PathBuf> = input.iter().map(|path| input_name(path)).collect();
+
+    for (path, name) in input.iter().zip(names.iter()) {
+        match parse_input(path) {
+            Ok(sections) => files.push((name, sections)),
+            Err(err) => errors.append(err)
+        }
+    };
+
+    // without every module, compiling would only add follow-up errors
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let base = common_dir(&input);
+    let paths: Vec<Option<PathBuf>> = input.iter().map(|path| options.output_path(path, &base)).collect();
+    let header_paths: Vec<Option<(PathBuf, Option<PathBuf>)>> = paths.iter()
+        .map(|path| path.as_ref().and_then(|path| options.header_paths(path)))
+        .collect();
+    let dep_paths: Vec<PathBuf> = match (&options.dep_file, options.make_deps) {
+        (Some(dep_file), true) => vec![dep_file.clone()],
+        (None, true) => paths.iter().flatten().map(|path| path.with_extension("d")).collect(),
+        (_, false) => Vec::new()
+    };
+    let header_files = header_paths.iter().flatten().flat_map(|(header, private)| std::iter::once(header).chain(private));
+    let targets: Vec<&PathBuf> = paths.iter().flatten().chain(header_files).chain(dep_paths.iter()).collect();
+    errors.append(duplicate_outputs(targets.iter().map(|path| path.as_path())));
+
+    // imported files are sources as well
+    let library = import(&files, &options.include_dirs, true)?;
+    let sources: Vec<&PathBuf> = input.iter().filter(|source| !is_stdin(source)).chain(library.iter().map(|(path, _)| path)).collect();
+    for path in targets {
+        if let Some(source) = sources.iter().find(|source| same_file(source, path)) {
+            errors.append(MmgxError::overwrite_input(path, source));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let deps = dependencies(&files, &library);
+
+    // imported files are compiled on their own, outputs using their modules include what they generate
+    let imported: Vec<PathBuf> = library.iter()
+        .map(|file| {
+            let output = options.derived_path(&file.0, &base);
+            options.header_paths(&output).map_or(output, |paths| paths.0)
+        })
+        .collect();
+    let imports = |i: usize, dir: &Path| -> String {
+        deps[i].iter().skip(1)
+            .filter_map(|dep| library.iter().position(|file| file.0 == **dep))
+            .map(|j| include(&imported[j], dir))
+            .collect()
+    };
+
+    // the C code includes its header, or without one the outputs of its imports
+    let includes: Vec<String> = paths.iter().zip(header_paths.iter()).enumerate()
+        .map(|(i, (path, header_paths))| match header_paths {
+            Some((header, _)) => include(header, header.parent().unwrap_or(Path::new(""))),
+            None => imports(i, path.as_deref().and_then(Path::parent).unwrap_or(Path::new("")))
+        })
+        .collect();
+    let outputs = compile(&files, &library, &includes, options.emit_header, options.line_directives)?;
+
+    let all_headers: Vec<Option<PathBuf>> = header_paths.iter().map(|paths| paths.as_ref().map(|paths| paths.0.clone())).collect();
+
+    let mut printed = Vec::new();
+    let mut written = Vec::new();
+    let mut rules = Vec::new();
+    for (i, ((path, output), header_paths)) in paths.into_iter().zip(outputs).zip(header_paths).enumerate() {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                printed.push(output.source);
+                continue;
+            }
+        };
+
+        let mut targets = vec![path.clone()];
+        if let Some((header, private)) = header_paths {
+            let dir = header.parent().unwrap_or(Path::new(""));
+
+            // the headers of the other outputs and imports whose modules this one uses
+            let mut includes: String = deps[i].iter().skip(1)
+                .filter_map(|dep| files.iter().position(|file| file.0 == *dep))
+                .filter_map(|i| all_headers[i].as_deref())
+                .map(|path| include(path, dir))
+                .collect();
+            includes += &imports(i, dir);
+
+            match private {
+                Some(private) => {
+                    let (exported, helpers): (Vec<_>, Vec<_>) = output.definitions.into_iter().partition(|part| part.0);
+                    includes += &include(&private, dir);
+                    let exported: String = exported.into_iter().map(|part| part.1).collect();
+                    let helpers: String = helpers.into_iter().map(|part| part.1).collect();
+                    written.push((header.clone(), guard_header(&header, &(includes + "\n" + &exported))));
+                    written.push((private.clone(), guard_header(&private, &helpers)));
+                    targets.extend([header, private]);
+                },
+                None => {
+                    let definitions: String = output.definitions.into_iter().map(|part| part.1).collect();
+                    if !includes.is_empty() {
+                        includes += "\n";
+                    }
+                    written.push((header.clone(), guard_header(&header, &(includes + &definitions))));
+                    targets.push(header);
+                }
+            }
+        }
+
+        // stdin has no file Make could check
+        let deps: Vec<&PathBuf> = deps[i].iter().copied().filter(|dep| dep.as_os_str() != parse::STDIN_PATH).collect();
+        rules.push(make_rule(&targets.iter().collect::<Vec<_>>(), &deps));
+        written.push((path, output.source));
+    }
+
+    match &options.dep_file {
+        Some(_) => written.extend(dep_paths.into_iter().zip([rules.concat()])),
+        None => written.extend(dep_paths.into_iter().zip(rules))
+    }
+
+    let generated = Generated {
+        outputs: written.iter().map(|output| output.0.clone()).collect(),
+        sources: input.iter()
+            .filter(|path| !is_stdin(path))
+            .chain(library.iter().map(|file| &file.0))
+            .cloned()
+            .collect()
+    };
+
+    write_outputs(written)?;
+
+    let mut stdout = std::io::stdout().lock();
+    for output in printed {
+        if let Err(err) = stdout.write_all(output.as_bytes()) {
+            return Err(MmgxError::file_write_error(Path::new("<stdout>"), err));
+        }
+    }
+    Ok(generated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse::{BinaryOp, Expr, UnaryOp};
+
+    fn num(num: i64) -> Box<Expr>
+    {
+        Box::new(Expr::Number(num))
+    }
+
+    fn var(name: &str) -> Box<Expr>
+    {
+        Box::new(Expr::Variable(String::from(name)))
+    }
+
+    fn binary(op: BinaryOp, lhs: Box<Expr>, rhs: Box<Expr>) -> Box<Expr>
+    {
+        Box::new(Expr::Binary(op, lhs, rhs))
+    }
+
+    fn compiled(source: &str) -> String
+    {
+        compile_sources([("test.x", source)]).unwrap().remove(0)
+    }
+
+    #[test]
+    fn compile_errors_point_at_their_statement()
+    {
+        let err = compile_sources([("test.x", "int x;\n  @M {\n\tA @eval(1/0)\n\tB @eval(q)\n\n\t@use Nope\n}\n")]).unwrap_err();
+        let positions: Vec<(usize, usize)> = err.diagnostics().iter().map(|diagnostic| match diagnostic.kind() {
+            MmgxErrorKind::CompileError(err) => (err.line(), err.column()),
+            kind => panic!("{:?}", kind)
+        }).collect();
+        assert_eq!(positions, [(2, 1), (3, 1), (5, 1)]);
+        assert!(err.to_string().contains("error at test.x:4:2 While Compiling M:"));
+    }
+
+    #[test]
+    fn blocks()
+    {
+        let res = compiled("@M {\n\tF(a, b) {\n\t\tint x = a; // first\n\t\tx += b; /* second */\n\t}\n\tT<N>(x) {\n\t\tx * N\n\t}\n\t@impl T<1..1>\n\tAFTER 1\n}\n");
+        assert!(res.contains("#define __M_F(a, b) \\\n\t\tint x = a; \\\n\t\tx += b;\n"), "{}", res);
+        assert!(res.contains("#define __M_T_1(x) \\\n\t\tx * 1\n"), "{}", res);
+        assert!(res.contains("#define __M_AFTER 1\n"), "{}", res);
+    }
+
+    #[test]
+    fn braces_without_arguments()
+    {
+        // only 'Name(args) {' opens a block
+        let res = compiled("@M {\n\tBEGIN {\n\tEND }\n}\n");
+        assert!(res.contains("#define __M_BEGIN {\n"), "{}", res);
+        assert!(res.contains("#define __M_END }\n"), "{}", res);
+        assert!(compile_sources([("test.x", "@M {\n\tF(a) {\n\t\ta\n}\n")]).is_err());
+    }
+
+    #[test]
+    fn line_continuations()
+    {
+        let res = compiled("@M {\n\tG(a) a + \\\n\t\t1\n\tH 2\n}\n");
+        assert!(res.contains("#define __M_G(a) a + \\\n\t\t1\n"), "{}", res);
+        assert!(res.contains("#define __M_H 2\n"), "{}", res);
+    }
+
+    fn out_dir_options() -> Options
+    {
+        Options {
+            extension: String::from("c"),
+            out_dir: Some(PathBuf::from("out")),
+            output: None,
+            stdout: false,
+            make_deps: false,
+            dep_file: None,
+            include_dirs: Vec::new(),
+            emit_header: false,
+            private_header: false,
+            line_directives: false
+        }
+    }
+
+    // each output line with the source line its `#line` directive assigns to it
+    fn traced(output: &str) -> Vec<(usize, &str)>
+    {
+        let mut line = 0;
+        let mut res = Vec::new();
+        for text in output.lines() {
+            match text.strip_prefix("#line ") {
+                Some(directive) => {
+                    assert!(directive.ends_with(" \"test.x\""), "{}", directive);
+                    line = directive.split(' ').next().unwrap().parse().unwrap();
+                },
+                None => {
+                    res.push((line, text));
+                    line += 1;
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn line_directives()
+    {
+        let source = "int a;\n@M {\n\tA 1\n\tB(x) {\n\t  x +\n\t  x\n\t}\n\tT<N> N\n\t@impl T<1..2>\n\tC 3\n}\nint b;\n";
+        let path = PathBuf::from("test.x");
+        let files = vec![(&path, parse::parse_source("test.x", source).unwrap())];
+        let output = compile(&files, &[], &[], false, true).unwrap().remove(0).source;
+        let traced = traced(&output);
+
+        let lines: Vec<&str> = source.lines().collect();
+        let line_of = |text: &str| traced.iter().find(|e| e.1 == text).unwrap_or_else(|| panic!("{} not in {}", text, output)).0;
+        // C code keeps its lines, defines point at their object, continued ones stay in step
+        assert_eq!(lines[line_of("int a;")-1], "int a;");
+        assert_eq!(lines[line_of("int b;")-1], "int b;");
+        assert_eq!(line_of("#define __M_A 1"), 3);
+        assert_eq!(line_of("#define __M_B(x) \\"), 4);
+        assert_eq!(line_of("\t  x"), 6);
+        assert_eq!(line_of("#define __M_T_1 1"), 8);
+        assert_eq!(line_of("#define __M_T_2 2"), 8);
+        assert_eq!(line_of("#define __M_C 3"), 10);
+    }
+
+    #[test]
+    fn out_dir_mirrors_the_inputs()
+    {
+        let options = out_dir_options();
+        let outputs = |inputs: &[&str]| {
+            let inputs: Vec<PathBuf> = inputs.iter().map(PathBuf::from).collect();
+            let base = common_dir(&inputs);
+            inputs.iter().filter_map(|path| options.output_path(path, &base)).collect::<Vec<_>>()
+        };
+        assert_eq!(outputs(&["a/b.x", "c.x"]), [PathBuf::from("out/a/b.c"), PathBuf::from("out/c.c")]);
+        assert_eq!(outputs(&["src/a/b.x", "src/c.x"]), [PathBuf::from("out/a/b.c"), PathBuf::from("out/c.c")]);
+        assert_eq!(outputs(&["./a/../b.x"]), [PathBuf::from("out/b.c")]);
+        // builds outside of the sources
+        assert_eq!(outputs(&["../src/a.x", "../src/lib/b.x"]), [PathBuf::from("out/a.c"), PathBuf::from("out/lib/b.c")]);
+        assert_eq!(outputs(&["a/../../b.x", "-"]), [PathBuf::from("out/b.c")]);
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(outputs(&[cwd.join("a/b.x").to_str().unwrap(), "c/d.x"]), [PathBuf::from("out/a/b.c"), PathBuf::from("out/c/d.c")]);
+    }
+
+    #[test]
+    fn outputs_are_written_once()
+    {
+        let paths = ["out/a.c", "out/b.c", "./out/a.c", "out/x/../a.c", "out/b.h"].map(Path::new);
+        let errors = duplicate_outputs(paths);
+        assert_eq!(errors.diagnostics().len(), 1);
+        assert_eq!(errors.diagnostics()[0].path(), Path::new("./out/a.c"));
+        assert!(duplicate_outputs(["a.c", "b.c"].map(Path::new)).is_empty());
+    }
+
+    #[test]
+    fn colliding_outputs_write_nothing()
+    {
+        let dir = std::env::temp_dir().join(format!("mmgx-test-{}", std::process::id()));
+        let target = dir.join("a.c");
+        let outputs = vec![(target.clone(), String::from("1")), (dir.join("b.c"), String::from("2")), (dir.join(".").join("a.c"), String::from("3"))];
+        assert!(write_outputs(outputs).is_err());
+        assert!(!dir.exists());
+
+        write_outputs(vec![(target.clone(), String::from("1")), (dir.join("b.c"), String::from("2"))]).unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "1");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // a fresh directory holding the given files
+    fn tree(name: &str, files: &[(&str, &str)]) -> PathBuf
+    {
+        let dir = std::env::temp_dir().join(format!("mmgx-{}-{}", name, std::process::id()));
+        for (path, content) in files {
+            create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(path), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn imports()
+    {
+        let dir = tree("import", &[
+            ("lib/lib.x", "@Lib {\n\tONE 1\n}\n"),
+            ("src/util.x", "@Util {\n\t@import \"lib.x\"\n\t@use Lib\n\tTWO Lib::ONE + Lib::ONE\n}\n"),
+            ("src/main.x", "@Main {\n\t@import \"util.x\"\n\t@import \"lib.x\"\n\t@use Util\n\t@export THREE Util::TWO + 1\n}\n")
+        ]);
+        let main = vec![dir.join("src/main.x")];
+
+        let (_, text) = expand_files(main.clone(), &[dir.join("lib")], "Main_THREE").unwrap();
+        assert_eq!(text, "1 + 1 + 1");
+
+        let err = expand_files(main, &[], "Main_THREE").unwrap_err();
+        assert!(err.diagnostics().iter().all(|d| matches!(d.kind(), MmgxErrorKind::CompileError(e) if matches!(e.kind(), CompileErrorKind::ImportNotFound(name) if name == "lib.x"))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reports()
+    {
+        let dir = tree("test", &[
+            ("t.x", "int x;\n@T {\n\tONE 1\n\tADD(a,b) ((a)+(b))\n\t@test ADD(ONE, 2) => ((1)+(2))\n\t  @test __T_ONE => 1\n\t@test ONE => 2\n\t@test ADD(1) => 1\n}\n")
+        ]);
+        let results = test_files(vec![dir.join("t.x")], &[]).unwrap();
+        let reports: Vec<(usize, usize, bool)> = results.iter().map(|test| (test.line, test.column, test.passed())).collect();
+        assert_eq!(reports, [(4, 1, true), (5, 3, true), (6, 1, false), (7, 1, false)]);
+
+        // names are resolved for the expansion only
+        assert_eq!(results[0].expression, "ADD(ONE, 2)");
+        assert_eq!(results[2].got.as_deref(), Ok("1"));
+        assert_eq!(results[3].got, Err(String::from("macro \"__T_ADD\" requires 2 arguments, but 1 given")));
+        assert_eq!(results[2].location(), format!("{}:7:2", dir.join("t.x").display()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn imported_outputs_are_included()
+    {
+        let dir = tree("include", &[
+            ("src/lib/lib.x", "@Lib {\n\tONE 1\n}\n"),
+            ("src/main.x", "int x;\n@Main {\n\t@import \"lib/lib.x\"\n\t@use Lib\n\t@export TWO Lib::ONE + 1\n}\n")
+        ]);
+        let out = dir.join("out");
+        let options = Options { out_dir: Some(out.clone()), ..out_dir_options() };
+        files(vec![dir.join("src/main.x")], &options).unwrap();
+        let main = std::fs::read_to_string(out.join("main.c")).unwrap();
+        assert!(main.starts_with("int x;\n#include \"lib/lib.c\"\n"), "{}", main);
+        // the import itself is compiled on its own
+        assert!(!out.join("lib").exists());
+
+        let options = Options { out_dir: Some(out.clone()), emit_header: true, ..out_dir_options() };
+        files(vec![dir.join("src/main.x")], &options).unwrap();
+        let main = std::fs::read_to_string(out.join("main.c")).unwrap();
+        let header = std::fs::read_to_string(out.join("main.h")).unwrap();
+        assert!(main.starts_with("int x;\n#include \"main.h\"\n"), "{}", main);
+        assert!(header.contains("#include \"lib/lib.h\"\n"), "{}", header);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn header_guards()
+    {
+        assert!(guard_header(Path::new("out/my-lib.h"), "x\n").starts_with("#ifndef MY_LIB_H\n#define MY_LIB_H\n\nx\n"));
+        assert!(guard_header(Path::new("2d.h"), "").ends_with("\n#endif /* _2D_H */\n"));
+    }
+
+    #[test]
+    fn private_headers()
+    {
+        let dir = tree("header", &[("m.x", "int x;\n@M {\n\tONE 1\n\t@export TWO ONE + 1\n}\nint y;\n")]);
+        let options = Options { out_dir: None, emit_header: true, private_header: true, ..out_dir_options() };
+        let generated = files(vec![dir.join("m.x")], &options).unwrap();
+        assert_eq!(generated.outputs, [dir.join("m.h"), dir.join("m_private.h"), dir.join("m.c")]);
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("m.c"), "int x;\n#include \"m.h\"\n\nint y;\n");
+        let header = read("m.h");
+        assert!(header.starts_with("#ifndef M_H\n#define M_H\n\n#include \"m_private.h\"\n"), "{}", header);
+        assert!(header.contains("#define M_TWO __M_ONE + 1\n") && !header.contains("#define __M_ONE"), "{}", header);
+        let private = read("m_private.h");
+        assert!(private.starts_with("#ifndef M_PRIVATE_H\n#define M_PRIVATE_H\n"), "{}", private);
+        assert!(private.contains("#define __M_ONE 1\n") && !private.contains("M_TWO"), "{}", private);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn imported_files_are_not_overwritten()
+    {
+        let dir = tree("overwrite", &[
+            ("a.x", "@A {\n\t@import \"lib.h\"\n}\n"),
+            ("lib.h", "@Lib {\n\tONE 1\n}\n")
+        ]);
+        let options = Options { output: Some(dir.join("lib.h")), ..out_dir_options() };
+        let Err(err) = files(vec![dir.join("a.x")], &options) else { panic!("the import was overwritten") };
+        assert!(matches!(err.diagnostics()[0].kind(), MmgxErrorKind::OverwriteInput(source) if source.ends_with("lib.h")), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn make_rules()
+    {
+        let (a, b, c) = (PathBuf::from("out/a b.c"), PathBuf::from("a b.x"), PathBuf::from("lib/$x#.x"));
+        assert_eq!(make_rule(&[&a], &[&b, &c]), "out/a\\ b.c: \\\n a\\ b.x \\\n lib/$$x\\#.x\n");
+        assert_eq!(make_rule(&[&a, &c], &[]), "out/a\\ b.c lib/$$x\\#.x:\n");
+    }
+
+    #[test]
+    fn dependency_files()
+    {
+        let dir = tree("deps", &[
+            ("lib/lib.x", "@Lib {\n\tONE 1\n}\n"),
+            ("src/util.x", "@Util {\n\t@import \"../lib/lib.x\"\n\t@use Lib\n\tTWO Lib::ONE + Lib::ONE\n}\n"),
+            ("src/main x.x", "@Main {\n\t@use Util\n\tTHREE Util::TWO + 1\n}\n"),
+            ("src/other.x", "@Other {\n\tFOUR 4\n}\n")
+        ]);
+        let src = dir.join("src");
+        let options = Options { out_dir: None, make_deps: true, ..out_dir_options() };
+        let generated = files(vec![src.join("main x.x"), src.join("util.x"), src.join("other.x")], &options).unwrap();
+        assert!(generated.sources.contains(&src.join("../lib/lib.x")));
+
+        let escape = |path: PathBuf| path.to_string_lossy().replace(' ', "\\ ");
+        let rule = std::fs::read_to_string(src.join("main x.d")).unwrap();
+        // the used module's file and the file it imports
+        assert_eq!(rule, format!("{}: \\\n {} \\\n {} \\\n {}\n",
+            escape(src.join("main x.c")), escape(src.join("main x.x")), escape(src.join("util.x")), escape(src.join("../lib/lib.x"))));
+        let rule = std::fs::read_to_string(src.join("other.d")).unwrap();
+        assert_eq!(rule, format!("{}: \\\n {}\n", escape(src.join("other.c")), escape(src.join("other.x"))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn import_cycles()
+    {
+        let dir = tree("cycle", &[
+            ("a.x", "@A {\n\t@import \"b.x\"\n}\n"),
+            ("b.x", "@B {\n\t@import \"a.x\"\n}\n")
+        ]);
+        let err = expand_files(vec![dir.join("a.x")], &[], "A").unwrap_err();
+        assert!(matches!(err.diagnostics()[0].kind(), MmgxErrorKind::CompileError(e) if matches!(e.kind(), CompileErrorKind::ImportCycle(_))), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn comparisons_are_no_template_calls()
+    {
+        assert_eq!(compiled("@M {\n\tLT(i,n) (i<n-1 && n>0)\n}\n"), "#define __M_LT(i, n) (i<n-1 && n>0)\n\n\n");
+        let output = compiled("@M {\n\tT<N> N\n\t@impl T<1..3>\n\tU(x) T<1+1> (x<3 || x>1)\n}\n");
+        assert!(output.contains("#define __M_U(x) __M_T_2 (x<3 || x>1)\n"), "{}", output);
+    }
+
+    fn range(start: i64, end: i64, inclusive: bool, step: Option<i64>) -> Result<Vec<i64>, CompileErrorKind>
+    {
+        parse::ValueSet::Range { start: *num(start), end: *num(end), inclusive, step: step.map(|step| *num(step)) }.values(&Scope::new())
+    }
+
+    #[test]
+    fn ranges()
+    {
+        assert_eq!(range(0, 3, true, None).unwrap(), [0, 1, 2, 3]);
+        assert_eq!(range(0, 3, false, None).unwrap(), [0, 1, 2]);
+        assert_eq!(range(3, 3, true, None).unwrap(), [3]);
+        assert_eq!(range(3, 3, false, None).unwrap(), []);
+        assert_eq!(range(0, 10, true, Some(3)).unwrap(), [0, 3, 6, 9]);
+        assert_eq!(range(0, 9, false, Some(3)).unwrap(), [0, 3, 6]);
+        assert_eq!(range(0, 9, true, Some(3)).unwrap(), [0, 3, 6, 9]);
+    }
+
+    #[test]
+    fn descending_ranges()
+    {
+        assert_eq!(range(3, 0, true, None).unwrap(), [3, 2, 1, 0]);
+        assert_eq!(range(3, 0, false, None).unwrap(), [3, 2, 1]);
+        assert_eq!(range(10, 0, true, Some(4)).unwrap(), [10, 6, 2]);
+        assert_eq!(range(i64::MIN+1, i64::MIN, true, Some(5)).unwrap(), [i64::MIN+1]);
+    }
+
+    #[test]
+    fn impl_value_sets()
+    {
+        let output = compiled("@M {\n\tT<A, B> A B\n\t@impl T<5..<0 step 2, {1, 3}>\n}\n");
+        let names: Vec<&str> = output.lines().filter_map(|line| line.split(' ').nth(1)).collect();
+        assert_eq!(names, ["__M_T_5_1", "__M_T_5_3", "__M_T_3_1", "__M_T_3_3", "__M_T_1_1", "__M_T_1_3"]);
+    }
+
+    #[test]
+    fn constants()
+    {
+        let output = compiled("@L {\n\t@let N = 2\n}\n@M {\n\t@use L\n\t@let K = N + 1\n\tT<A> A\n\t@impl T<N..K>\n\tX @eval(N * K)\n}\n");
+        let names: Vec<&str> = output.lines().filter_map(|line| line.split(' ').nth(1)).collect();
+        assert_eq!(names, ["__M_T_2", "__M_T_3", "__M_X"]);
+        assert!(output.contains("#define __M_X 6\n"), "{}", output);
+        // a module may shadow the constants it uses
+        assert!(compiled("@L {\n\t@let N = 2\n}\n@M {\n\t@use L\n\t@let N = N * 5\n\tX @eval(N)\n}\n").contains("#define __M_X 10\n"));
+
+        let error = |source: &str| compile_sources([("test.x", source)]).unwrap_err().to_string();
+        let err = error("@M {\n\t@let N = 1\n\t@let N = 2\n}\n");
+        assert!(err.contains("Constant N is defined twice"), "{}", err);
+        let err = error("@M {\n\t@let N = Q\n}\n");
+        assert!(err.contains("While Compiling N:") && err.contains("Unknown variable Q"), "{}", err);
+        // constants of modules that are not used are unknown
+        let err = error("@L {\n\t@let N = 2\n}\n@M {\n\tX @eval(N)\n}\n");
+        assert!(err.contains("Unknown variable N"), "{}", err);
+    }
+
+    #[test]
+    fn where_clauses()
+    {
+        let output = compiled("@M {\n\tT<A, B> A B\n\t@impl T<0..3, 0..3> where A < B && B != 2\n}\n");
+        let names: Vec<&str> = output.lines().filter_map(|line| line.split(' ').nth(1)).collect();
+        assert_eq!(names, ["__M_T_0_1", "__M_T_0_3", "__M_T_1_3", "__M_T_2_3"]);
+        assert!(!compiled("@M {\n\tT<A> A\n\t@impl T<0..3> where 0\n}\n").contains("#define"));
+
+        // only the combinations left by the clause count as implemented
+        let output = compiled("@M {\n\tT<A> A\n\t@impl T<0..3> where A % 2 == 0\n\tX T<2>\n}\n");
+        assert!(output.contains("#define __M_X __M_T_2\n"), "{}", output);
+        let err = compile_sources([("test.x", "@M {\n\tT<A> A\n\t@impl T<0..3> where A % 2 == 0\n\tX T<1>\n}\n")]).unwrap_err();
+        assert!(err.to_string().contains("T<1> is not covered by any @impl"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_steps()
+    {
+        assert!(matches!(range(0, 3, true, Some(0)), Err(CompileErrorKind::InvalidStep(0))));
+        assert!(matches!(range(3, 0, true, Some(-1)), Err(CompileErrorKind::InvalidStep(-1))));
+    }
+
+    #[test]
+    fn lists()
+    {
+        let scope: Scope = vec![("N", 4)];
+        let list = parse::ValueSet::List(vec![*num(5), *var("N"), *binary(BinaryOp::Mul, var("N"), num(2)), *num(5)]);
+        assert_eq!(list.values(&scope).unwrap(), [5, 4, 8, 5]);
+        assert!(matches!(parse::ValueSet::List(vec![*var("M")]).values(&scope), Err(CompileErrorKind::UnknownVariable(_))));
+    }
+
+    #[test]
+    fn eval_arithmetic()
+    {
+        let scope: Scope = vec![("A", 7), ("B", 2), ("A", 9)];
+        assert_eq!(binary(BinaryOp::Add, var("A"), binary(BinaryOp::Mul, var("B"), num(3))).eval(&scope).unwrap(), 15);
+        assert_eq!(binary(BinaryOp::Div, num(-7), num(2)).eval(&scope).unwrap(), -3);
+        assert_eq!(binary(BinaryOp::Rem, num(-7), num(2)).eval(&scope).unwrap(), -1);
+        assert_eq!(binary(BinaryOp::LessEqual, var("B"), num(2)).eval(&scope).unwrap(), 1);
+        assert_eq!(Expr::Unary(UnaryOp::Not, var("B")).eval(&scope).unwrap(), 0);
+        assert!(matches!(var("C").eval(&scope), Err(CompileErrorKind::UnknownVariable(name)) if name == "C"));
+    }
+
+    #[test]
+    fn eval_short_circuits()
+    {
+        let scope = Scope::new();
+        assert_eq!(binary(BinaryOp::And, num(0), binary(BinaryOp::Div, num(1), num(0))).eval(&scope).unwrap(), 0);
+        assert_eq!(binary(BinaryOp::Or, num(5), var("missing")).eval(&scope).unwrap(), 1);
+        assert_eq!(binary(BinaryOp::And, num(5), num(3)).eval(&scope).unwrap(), 1);
+    }
+
+    #[test]
+    fn eval_division_by_zero()
+    {
+        let scope = Scope::new();
+        assert!(matches!(binary(BinaryOp::Div, num(1), num(0)).eval(&scope), Err(CompileErrorKind::DivisionByZero)));
+        assert!(matches!(binary(BinaryOp::Rem, num(1), num(0)).eval(&scope), Err(CompileErrorKind::DivisionByZero)));
+    }
+
+    #[test]
+    fn eval_overflow()
+    {
+        let scope = Scope::new();
+        let overflows = [
+            binary(BinaryOp::Add, num(i64::MAX), num(1)),
+            binary(BinaryOp::Sub, num(i64::MIN), num(1)),
+            binary(BinaryOp::Mul, num(i64::MAX), num(2)),
+            binary(BinaryOp::Div, num(i64::MIN), num(-1)),
+            binary(BinaryOp::Rem, num(i64::MIN), num(-1)),
+            Box::new(Expr::Unary(UnaryOp::Negate, num(i64::MIN)))
+        ];
+        for expr in overflows {
+            assert!(matches!(expr.eval(&scope), Err(CompileErrorKind::ArithmeticOverflow)), "{:?}", expr);
+        }
+    }
+}
@@ -1,30 +1,50 @@
+mod expr;
+mod json;
+mod format;
+
 use logos::{Logos, Source};
-use std::{path::{Path,PathBuf}, rc::Rc, vec, fmt::Formatter, ops::{Range, RangeInclusive}, fs::read_to_string};
+use std::{path::{Path,PathBuf}, rc::Rc, fmt::Formatter, ops::Range, fs::read_to_string, io::Read};
 use super::MmgxError;
 
+pub use expr::{Expr, UnaryOp, BinaryOp};
+pub use json::{sections_json, document_json};
+pub use format::format_document;
+
 #[derive(Clone)]
 pub struct LexerInfo {
     line: usize,
-    line_start: usize,
-    line_start_last: usize,
     offset: usize,
     path: PathBuf
 }
 
+/// Lexes a piece of text on its own, outside of any file
+pub(crate) fn lexer(text: &str) -> logos::Lexer<'_, CodeToken>
+{
+    CodeToken::lexer_with_extras(text, LexerInfo {line: 0, path: Default::default(), offset: 0})
+}
+
 impl LexerInfo {
-    fn get(lex: &logos::Lexer<CodeToken>) -> Self
+    // keeps line information intact for tokens spanning several lines
+    fn count_lines(lex: &mut logos::Lexer<CodeToken>)
     {
-        let mut obj = lex.extras.clone();
-        obj.offset = lex.span().start;
-        obj
+        lex.extras.line+=lex.slice().matches('\n').count();
     }
-    fn line(lex: &logos::Lexer<CodeToken>) -> Option<String>
+    fn block_comment(lex: &mut logos::Lexer<CodeToken>) -> bool
     {
-        lex.source().slice(lex.extras.line_start..lex.span().end).map(|s| s.to_string())
+        match lex.remainder().find("*/") {
+            Some(end) => {
+                lex.bump(end+2);
+                Self::count_lines(lex);
+                true
+            },
+            None => false
+        }
     }
-    fn line_from(&self, lex: &logos::Lexer<CodeToken>) -> Option<String>
+    fn get(lex: &logos::Lexer<CodeToken>) -> Self
     {
-        lex.source().slice(self.line_start..lex.span().end).map(|s| s.to_string())
+        let mut obj = lex.extras.clone();
+        obj.offset = lex.span().start;
+        obj
     }
 }
 
@@ -38,12 +58,18 @@ pub enum CodeToken{
     #[regex("[a-zA-Z_][a-zA-Z_0-9]*")]
     Identifier,
 
-    #[regex("\"([^\"\\\\]\\.)*\"")]
+    #[regex(r#""([^"\\\n]|\\.)*""#)]
     String,
 
+    #[regex(r"'([^'\\\n]|\\.)*'")]
+    Char,
+
     #[regex("//.*")]
     Comment,
 
+    #[token("/*", LexerInfo::block_comment)]
+    BlockComment,
+
     #[regex("[0-9]+")]
     Number,
 
@@ -77,93 +103,159 @@ pub enum CodeToken{
     #[token("::")]
     ScopeResolution,
 
-    #[token("\r\n", |lex| lex.extras.line+=1; lex.extras.line_start_last=lex.extras.line_start; lex.extras.line_start=lex.span().end)]
-    #[token("\n", |lex| lex.extras.line+=1; lex.extras.line_start_last=lex.extras.line_start; lex.extras.line_start=lex.span().end)]
+    #[token("+")]
+    Plus,
+    #[token("-")]
+    Minus,
+    #[token("*")]
+    Star,
+    #[token("/")]
+    Slash,
+    #[token("%")]
+    Percent,
+
+    #[token("<=")]
+    LessEqual,
+    #[token(">=")]
+    GreaterEqual,
+    #[token("==")]
+    Equal,
+    #[token("!=")]
+    NotEqual,
+
+    #[token("&&")]
+    And,
+    #[token("||")]
+    Or,
+    #[token("!")]
+    Not,
+
+    #[token("=")]
+    Assign,
+
+    #[token("\r\n", |lex| lex.extras.line+=1)]
+    #[token("\n", |lex| lex.extras.line+=1)]
     NewLine,
 
     #[token(",")]
     Comma,
 
+    #[regex(r"\\[ \t]*(\r)?\n", |lex| lex.extras.line+=1)]
+    LineContinuation,
+
     #[regex(r"[ \t]+")]
     Whitespace,
 
     Unknown,
 }
 
-struct UnexpectedToken {
-    got: Result<CodeToken, ()>,
-    expected: Vec<CodeToken>,
+#[derive(Debug)]
+pub struct UnexpectedToken {
+    pub got: Result<CodeToken, ()>,
+    pub expected: Vec<CodeToken>,
 }
 
-struct WrongArgument {
-    function: String,
-    got: String,
-    expected: Vec<String>
+#[derive(Debug)]
+pub struct WrongArgument {
+    pub function: String,
+    pub got: String,
+    pub expected: Vec<String>
 }
 
-enum ParseErrorKind {
+#[derive(Debug)]
+pub enum ParseErrorKind {
     UnexpectedToken(UnexpectedToken),
     UnexpectedEOF(Vec<CodeToken>),
     WrongArgument(WrongArgument),
-    UnknownCommand(String)
+    UnknownCommand(String),
+    // the list opened by the marked token lacks the closing one
+    Unclosed(CodeToken)
+}
+
+impl std::fmt::Display for ParseErrorKind {
+
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        match self {
+            ParseErrorKind::UnexpectedToken(ut) => write!(f, "Unexpected Token got {:?} expected {:?}", ut.got.as_ref().unwrap_or(&CodeToken::Unknown), ut.expected),
+            ParseErrorKind::UnexpectedEOF(expected) => write!(f, "Reached End of File expected Token {:?}", expected),
+            ParseErrorKind::WrongArgument(wa) => write!(f, "Wrong Argument for {} got {} expected {:?}", wa.function, wa.got, wa.expected),
+            ParseErrorKind::UnknownCommand(cmd) => write!(f, "Unknown Command '{}'", cmd),
+            ParseErrorKind::Unclosed(end) => write!(f, "Unclosed list expected Token {:?} before the end of the line", end)
+        }
+    }
 }
 
+#[derive(Debug)]
 pub struct ParseError {
     line: usize,
     line_start: usize,
     span: Range<usize>,
     line_str: Option<String>,
-    kind: ParseErrorKind,
+    // boxed to keep results carrying the error small
+    kind: Box<ParseErrorKind>,
 }
 
 impl ParseError {
-    fn create(lex: &logos::Lexer<CodeToken>, kind: ParseErrorKind) -> Self
+    /// Line of the error, counted from 0
+    pub fn line(&self) -> usize
     {
-        Self { line: lex.extras.line,
-               line_start: lex.extras.line_start,
-               span: lex.span(),
-               line_str: LexerInfo::line(lex),
-               kind
-        }
+        self.line
     }
 
-    fn unexpected_token(lex: &logos::Lexer<CodeToken>, got: Option<Result<CodeToken, ()>>, expected: Vec<CodeToken>) -> Self
+    /// Byte offset of the error within its line
+    pub fn column(&self) -> usize
     {
-        let mut line = lex.extras.line;
-        let mut line_start= lex.extras.line_start;
-
-        let reason = match got {
-            Some(t) => {
-                if t==Ok(CodeToken::NewLine) {
-                    line-=1;
-                    line_start = lex.extras.line_start_last;
-                };
-                ParseErrorKind::UnexpectedToken(UnexpectedToken { got: t, expected })
-            },
-            None => ParseErrorKind::UnexpectedEOF(expected)
-        };
+        self.span.start-self.line_start
+    }
+
+    /// Byte range of the offending source
+    pub fn span(&self) -> Range<usize>
+    {
+        self.span.clone()
+    }
+
+    pub fn kind(&self) -> &ParseErrorKind
+    {
+        &self.kind
+    }
+
+    // The line is found from where the span starts, tokens like newlines and
+    // line continuations already moved the lexer on to the next one
+    fn at(lex: &logos::Lexer<CodeToken>, span: Range<usize>, kind: ParseErrorKind) -> Self
+    {
+        let before = &lex.source()[..span.start];
+        let line_start = before.rfind('\n').map_or(0, |i| i+1);
 
-        Self { line,
+        Self { line: before.matches('\n').count(),
                line_start,
-               span: lex.span(),
-               line_str:
-                   if let Some(string) = lex.source().slice(line_start..lex.span().end) {
-                       Some(String::from(string))
-                   }else{
-                       None
-                   },
-               kind: reason
+               line_str: lex.source().slice(line_start..span.end).map(String::from),
+               span,
+               kind: Box::new(kind)
         }
     }
 
+    fn create(lex: &logos::Lexer<CodeToken>, kind: ParseErrorKind) -> Self
+    {
+        Self::at(lex, lex.span(), kind)
+    }
+
+    fn unexpected_token(lex: &logos::Lexer<CodeToken>, got: Option<Result<CodeToken, ()>>, expected: Vec<CodeToken>) -> Self
+    {
+        Self::unexpected_token_at(lex, got, lex.span(), expected)
+    }
+
+    fn unexpected_token_at(lex: &logos::Lexer<CodeToken>, got: Option<Result<CodeToken, ()>>, span: Range<usize>, expected: Vec<CodeToken>) -> Self
+    {
+        Self::at(lex, span, match got {
+            Some(t) => ParseErrorKind::UnexpectedToken(UnexpectedToken { got: t, expected }),
+            None => ParseErrorKind::UnexpectedEOF(expected)
+        })
+    }
+
     fn wrong_argument(lex: &logos::Lexer<CodeToken>, pos: LexerInfo, function: String, got: String, expected: Vec<String>) -> Self
     {
-        Self { line: pos.line,
-               line_start: pos.line_start,
-               span: pos.offset..lex.span().end,
-               line_str: pos.line_from(lex),
-               kind: ParseErrorKind::WrongArgument(WrongArgument{function, got, expected})
-        }
+        Self::at(lex, pos.offset..lex.span().end, ParseErrorKind::WrongArgument(WrongArgument{function, got, expected}))
     }
 
     fn unknown_command(lex: &logos::Lexer<CodeToken>) -> Self
@@ -171,9 +263,16 @@ impl ParseError {
         Self::create(lex, ParseErrorKind::UnknownCommand(String::from(lex.slice())))
     }
 
-    pub fn fmt(&self, f: &mut Formatter, path: &Path) -> std::fmt::Result {
-        write!(f, "error at {}:{}:{} ", path.to_str().unwrap(), self.line, self.span.start-self.line_start)?;
-        match &self.kind {
+    fn unknown_command_at(lex: &logos::Lexer<CodeToken>, command: &str, span: Range<usize>) -> Self
+    {
+        let mut err = Self::unexpected_token_at(lex, None, span, Vec::new());
+        err.kind = Box::new(ParseErrorKind::UnknownCommand(String::from(command)));
+        err
+    }
+
+    pub(crate) fn fmt(&self, f: &mut Formatter, path: &Path) -> std::fmt::Result {
+        write!(f, "error at {} ", super::location(path, self.line, self.span.start-self.line_start))?;
+        match self.kind.as_ref() {
             ParseErrorKind::UnexpectedToken(ut) =>{
                 write!(f, "Unexpected Token got {:?}", ut.got.as_ref().unwrap_or(&CodeToken::Unknown))?;
                 if let Some(line) = &self.line_str {
@@ -181,15 +280,7 @@ impl ParseError {
                 }
                 writeln!(f, " expected {:?}", ut.expected)?;
             },
-            ParseErrorKind::UnexpectedEOF(expected) => {
-                writeln!(f, "Reached End of File expected Token {:?}", expected)?;
-            },
-            ParseErrorKind::WrongArgument(wa) => {
-                writeln!(f, "Wrong Argument for {} got {} expected {:?}", wa.function, wa.got, wa.expected)?;
-            },
-            ParseErrorKind::UnknownCommand(cmd) => {
-                writeln!(f, "Unknown Command '{}'", cmd)?;
-            }
+            kind => writeln!(f, "{}", kind)?
 
         };
 
@@ -197,15 +288,17 @@ impl ParseError {
         if let Some(line) = &self.line_str {
             f.write_str(line.as_str())?;
 
+            // one mark per character, the line may hold multi-byte ones
+            let column = self.span.start-self.line_start;
             let mut tmp = String::from("\n");
-            for i in 0..(self.span.start-self.line_start) {
-                match line.slice(i..(i+1)).unwrap() {
-                    "\t" => tmp+="     ",
-                    "\n" | "\r" => tmp.clear(),
+            for (_, c) in line.char_indices().take_while(|(i, _)| *i < column) {
+                match c {
+                    '\t' => tmp+="     ",
+                    '\n' | '\r' => tmp.clear(),
                     _ => tmp+=" "
                 }
             };
-            for _ in self.span.clone() {
+            for _ in line.get(column..).unwrap_or_default().chars() {
                 tmp+="~";
             };
             f.write_str(tmp.as_str())?;
@@ -227,10 +320,18 @@ pub struct External{
     pub implement: bool
 }
 
+#[derive(Debug)]
+pub enum TemplateArgument {
+    Expr(Expr),
+    Macro(String)
+}
+
 #[derive(Debug)]
 pub struct TemplateCall {
     pub name: String,
-    pub args: Vec<String>,
+    pub args: Vec<TemplateArgument>,
+    /// Source text of the call, kept as is if `name` turns out to be no template
+    pub text: String
 }
 
 #[derive(Debug)]
@@ -240,13 +341,22 @@ pub enum BodyStatement {
     OptDependency(String),
     External(External),
     TemplateCall(TemplateCall),
+    Eval(Expr),
 }
 
 
 #[derive(Debug)]
 pub struct Object{
     pub name: String,
-    pub body: Vec<BodyStatement>
+    /// Macro arguments, also fed back as the first two body statements
+    pub args: Option<Vec<String>>,
+    pub body: Vec<BodyStatement>,
+    /// Line of the name, counted from 0
+    pub line: usize,
+    /// Source range of the definition
+    pub span: Range<usize>,
+    /// Source range of each body statement
+    pub spans: Vec<Range<usize>>
 }
 
 #[derive(Debug, PartialEq)]
@@ -261,17 +371,39 @@ pub struct Template {
     pub obj: Object
 }
 
+#[derive(Debug)]
+pub enum ValueSet {
+    Range{start: Expr, end: Expr, inclusive: bool, step: Option<Expr>},
+    List(Vec<Expr>)
+}
+
 #[derive(Debug)]
 pub struct CommandImpl {
     pub template: String,
-    pub params: Vec<RangeInclusive<usize>>
+    pub params: Vec<ValueSet>,
+    pub condition: Option<Expr>
+}
+
+#[derive(Debug)]
+pub struct CommandLet {
+    pub name: String,
+    pub value: Expr
+}
+
+#[derive(Debug)]
+pub struct CommandTest {
+    pub expression: String,
+    pub expected: String
 }
 
 #[derive(Debug)]
 pub enum Command {
     Impl(CommandImpl),
+    Let(CommandLet),
     Use(String),
-    Export(Object)
+    Import(String),
+    Export(Object),
+    Test(CommandTest)
 }
 
 #[derive(Debug)]
@@ -282,18 +414,72 @@ pub enum Statement {
     Comment(String)
 }
 
+#[derive(Debug)]
 pub struct MmgxModule {
     pub name: String,
     pub body: Vec<Statement>,
-    pub line: usize
+    /// Line of the closing '}', counted from 0
+    pub line: usize,
+    /// Source range from '@' to the closing '}'
+    pub span: Range<usize>,
+    /// Source range of each statement
+    pub spans: Vec<Range<usize>>,
+    // line of the '@' and the offsets the lines of the module start at
+    first_line: usize,
+    line_starts: Vec<usize>
+}
+
+impl MmgxModule {
+    /// Line and byte column of a source offset within the module, both counted from 0
+    pub fn position(&self, offset: usize) -> (usize, usize)
+    {
+        let index = self.line_starts.partition_point(|start| *start <= offset).max(1) - 1;
+        (self.first_line + index, offset - self.line_starts[index])
+    }
 }
 
+#[derive(Debug, Clone)]
 pub enum Section {
-    CSource(String),
+    /// C code passed through, with its source range
+    CSource(String, Range<usize>),
     MmgxModule(Rc<MmgxModule>),
     // MmgxCall
 }
 
+type BodyToken<'a> = (Option<Result<CodeToken, ()>>, &'a str, Range<usize>);
+
+fn skip_whitespace(tokens: &[BodyToken], i: &mut usize)
+{
+    while matches!(tokens.get(*i), Some((Some(Ok(CodeToken::Whitespace)), _, _))) {
+        *i+=1;
+    }
+}
+
+fn unexpected_at(lex: &logos::Lexer<CodeToken>, tokens: &[BodyToken], i: usize, expected: Vec<CodeToken>) -> ParseError
+{
+    match tokens.get(i) {
+        Some(t) => ParseError::unexpected_token_at(lex, t.0.clone(), t.2.clone(), expected),
+        None => {
+            let end = tokens.last().map_or(lex.span().start, |t| t.2.end);
+            ParseError::unexpected_token_at(lex, None, end..end, expected)
+        }
+    }
+}
+
+// Collects the rest of the line without comments, the NewLine itself is consumed
+fn collect_line<'a>(lex: &mut logos::Lexer<'a, CodeToken>) -> Vec<BodyToken<'a>>
+{
+    let mut tokens = Vec::new();
+    while let Some(t) = lex_next(lex) {
+        match t {
+            Ok(CodeToken::NewLine) => break,
+            Ok(CodeToken::Comment | CodeToken::BlockComment) => {},
+            _ => tokens.push((Some(t), lex.slice(), lex.span()))
+        }
+    }
+    tokens
+}
+
 fn lex_next(lex: &mut logos::Lexer<CodeToken>) -> Option<Result<CodeToken, ()>>
 {
     let token = lex.next();
@@ -305,8 +491,9 @@ fn next_non_whitespace(lex: &mut logos::Lexer<CodeToken>) -> Option<Result<CodeT
     loop{
         match lex_next(lex) {
             Some(Ok(CodeToken::Whitespace)) |
-            Some(Ok(CodeToken::Comment)) =>{},
-            t @ _ =>{return t;}
+            Some(Ok(CodeToken::Comment)) |
+            Some(Ok(CodeToken::BlockComment)) =>{},
+            t =>{return t;}
         };
     }
 }
@@ -321,18 +508,18 @@ fn parse_mmgx_parameters_body<R, const N: usize, const V: usize, F: Fn([&str; N]
     let mut expect = Vec::new();
     let mut token = None;
 
-    for c in 0..V {
+    for option in capture {
 
         let mut iter = tokens.iter();
         found = true;
 
-        for i in 0..N {
+        for (i, expected) in option.iter().enumerate() {
             if let Some(t) = iter.next() {
                 token = Some(t.0.clone());
-                if t.0.clone().map_or(false, |x| x == capture[c][i]) {
+                if t.0.clone().is_ok_and(|x| &x == expected) {
                     tmp[i] = t.1;
                 } else {
-                    expect.push(capture[c][i].clone());
+                    expect.push(expected.clone());
                     found = false;
                     break;
                 }
@@ -356,17 +543,23 @@ fn parse_mmgx_parameters_body<R, const N: usize, const V: usize, F: Fn([&str; N]
 fn parse_mmgx_parameters<R, const N: usize, const V: usize, F: Fn([&str; N]) -> Result<R, ParseError> >(lex: &mut logos::Lexer<CodeToken>, capture: [[CodeToken; N]; V], func: F, end: CodeToken) -> Result<Vec<R>, ParseError>
 {
     let mut params = Vec::new();
+    let open = lex.span();
 
     let mut tokens: Vec<(Result<CodeToken, ()>, &str)> = Vec::new();
 
-    while let Some(t) = next_non_whitespace(lex) {
+    loop {
+        let t = match next_non_whitespace(lex) {
+            // a list ends on its line, the error points at where it was opened
+            Some(Ok(CodeToken::NewLine)) | None => {return Err(ParseError::at(lex, open, ParseErrorKind::Unclosed(end)));},
+            Some(t) => t
+        };
         match t {
             Ok(CodeToken::Comma) => {
                 params.push(parse_mmgx_parameters_body(lex, tokens, &capture, &func)?);
                 tokens = Vec::new();
             },
             _ => {
-                if t.as_ref().ok().map_or(false, |x| x==&end) {
+                if t.as_ref().ok() == Some(&end) {
                     params.push(parse_mmgx_parameters_body(lex, tokens, &capture, &func)?);
                     break;
                 } else {
@@ -378,144 +571,231 @@ fn parse_mmgx_parameters<R, const N: usize, const V: usize, F: Fn([&str; N]) ->
     Ok(params)
 }
 
-fn parse_mmgx_object_body<'a>(tokens: &Vec<(Option<Result<CodeToken, ()>>, &'a str)>, i: &mut usize, params: &Option<Vec<TemplateParameter>>, args: &Option<Vec<String>>) -> Option<BodyStatement>
+fn parse_mmgx_object_body<'a>(lex: &logos::Lexer<'a, CodeToken>, tokens: &[BodyToken<'a>], i: &mut usize, params: &Option<Vec<TemplateParameter>>, args: &Option<Vec<String>>) -> Option<BodyStatement>
 {
-    let t1 = tokens.get(*i).unwrap_or(&(None, ""));
-    let t2 = tokens.get(*i+1).unwrap_or(&(None, ""));
-    let t3 = tokens.get(*i+2).unwrap_or(&(None, ""));
-
-    let mut implement = None;
+    let t1 = tokens.get(*i).unwrap_or(&(None, "", 0..0));
+    let t2 = tokens.get(*i+1).unwrap_or(&(None, "", 0..0));
+    let t3 = tokens.get(*i+2).unwrap_or(&(None, "", 0..0));
 
     // [MODULE]::[OBJECT]
     // [MODULE].[OBJECT]
 
-    match t2.0 {
-        Some(Ok(CodeToken::Dot)) => implement = Some(true),
-        Some(Ok(CodeToken::ScopeResolution)) => implement = Some(false),
+    let implement = match t2.0 {
+        Some(Ok(CodeToken::Dot)) => Some(true),
+        Some(Ok(CodeToken::ScopeResolution)) => Some(false),
         Some(Ok(CodeToken::DiamondOpen)) => {
-            let mut arg_list: Vec<&String> = Vec::new();
-
-            if let Some(params) = params {
-                for p in params {
-                    match p {
-                        TemplateParameter::Param(string) => arg_list.push(string),
-                        _=>{}
-                    }
-                }
+            if t1.0 != Some(Ok(CodeToken::Name)) {
+                return None;
             }
-            if let Some(args) = args {
-                for s in args {
-                    arg_list.push(s);
-                }
 
-            }
+            let is_param = |name: &str| params.as_ref().is_some_and(|p| p.contains(&TemplateParameter::Param(String::from(name))));
+            let is_arg = |name: &str| args.as_ref().is_some_and(|a| a.iter().any(|e| e==name));
 
             let mut list = Vec::new();
-            let mut index = 0;
-
-            let tokens: Vec<&(Option<Result<CodeToken, ()>>, &str)> = tokens.iter().skip(*i+2).filter(|e| !matches!(e.0, Some(Ok(CodeToken::Whitespace)))).collect();
-
-            while index < tokens.len()-1 {
-                match tokens[index].0 {
-                    Some(Ok(CodeToken::Name)) => {
-                        if let Some(e) = arg_list.iter().find(|e| **e==&String::from(tokens[index].1)) {
-                            list.push((*e).clone());
-                        } else {
-                            return None;
-                        }
+            let mut index = *i+2;
+
+            loop {
+                skip_whitespace(tokens, &mut index);
+
+                // a plain macro argument is pasted into the name, everything else is evaluated
+                let mut next = index+1;
+                skip_whitespace(tokens, &mut next);
+                let arg = match (tokens.get(index), tokens.get(next).map(|t| &t.0)) {
+                    (Some((Some(Ok(CodeToken::Name)), name, _)), Some(Some(Ok(CodeToken::Comma | CodeToken::DiamondClose))))
+                        if is_arg(name) && !is_param(name) => {
+                        index = next;
+                        TemplateArgument::Macro(String::from(*name))
                     },
+                    _ => TemplateArgument::Expr(expr::parse_expr(lex, tokens, &mut index, false).ok()?)
+                };
+                list.push(arg);
+
+                skip_whitespace(tokens, &mut index);
+                match tokens.get(index).map(|t| &t.0) {
+                    Some(Some(Ok(CodeToken::Comma))) => index+=1,
+                    Some(Some(Ok(CodeToken::DiamondClose))) => break,
                     _ => {return None;}
                 }
-                match tokens[index+1].0 {
-                    Some(Ok(CodeToken::Comma)) => {},
-                    Some(Ok(CodeToken::DiamondClose)) => break,
-                    _ => {return None;}
-                };
-                index+=2;
-            }
-            match t1.0 {
-                Some(Ok(CodeToken::Name)) => {
-                    *i+=index+3;
-                    return Some(BodyStatement::TemplateCall(TemplateCall { name: String::from(t1.1), args: list }));
-                },
-                _ => {return None;}
             }
+
+            let text = tokens[*i..=index].iter().map(|t| t.1).collect();
+            *i = index;
+            return Some(BodyStatement::TemplateCall(TemplateCall { name: String::from(t1.1), args: list, text }));
         },
         _ => {return None;}
     };
 
-    if let Some(implement) = implement {
-        match t3.0 {
-        Some(Ok(CodeToken::Name)) => {
-            let res = Some(BodyStatement::External(
-                External {
-                    module: match t1.0 {
-                        Some(Ok(CodeToken::Name)) | Some(Ok(CodeToken::Identifier)) => String::from(t1.1),
-                        _ => {return None}
-                        },
-                    object: String::from(t3.1),
-                    implement
-                    }
-                ));
+    if let (Some(implement), Some(Ok(CodeToken::Name))) = (implement, &t3.0) {
+        let res = Some(BodyStatement::External(
+            External {
+                module: match t1.0 {
+                    Some(Ok(CodeToken::Name)) | Some(Ok(CodeToken::Identifier)) => String::from(t1.1),
+                    _ => {return None}
+                    },
+                object: String::from(t3.1),
+                implement
+                }
+            ));
 
-                *i+=2;
-                return res;
-            },
-            _ => {}
-        }
+        *i+=2;
+        return res;
     };
 
     None
 }
 
-fn parse_body(tokens: Vec<(Option<Result<CodeToken, ()>>, &str)>, params: &Option<Vec<TemplateParameter>>, args: &Option<Vec<String>>, va_args: bool) -> Vec<BodyStatement>
+// @eval(EXPR)
+fn parse_body_directive<'a>(lex: &logos::Lexer<'a, CodeToken>, tokens: &[BodyToken<'a>], i: &mut usize) -> Result<BodyStatement, ParseError>
+{
+    let unexpected = |i: usize, expected: Vec<CodeToken>| unexpected_at(lex, tokens, i, expected);
+
+    *i+=1;
+    match tokens.get(*i) {
+        Some((Some(Ok(CodeToken::Name)), "eval", _)) => {},
+        Some(t) if t.0 == Some(Ok(CodeToken::Name)) => {return Err(ParseError::unknown_command_at(lex, t.1, t.2.clone()));},
+        _ => {return Err(unexpected(*i, vec![CodeToken::Name]));}
+    }
+
+    *i+=1;
+    if !matches!(tokens.get(*i), Some((Some(Ok(CodeToken::ParenOpen)), _, _))) {
+        return Err(unexpected(*i, vec![CodeToken::ParenOpen]));
+    }
+
+    *i+=1;
+    let expr = expr::parse_expr(lex, tokens, i, true)?;
+
+    skip_whitespace(tokens, i);
+    match tokens.get(*i) {
+        Some((Some(Ok(CodeToken::ParenClose)), _, _)) => Ok(BodyStatement::Eval(expr)),
+        _ => Err(unexpected(*i, vec![CodeToken::ParenClose]))
+    }
+}
+
+// The body statements, each with its source range
+fn parse_body<'a>(lex: &logos::Lexer<'a, CodeToken>, tokens: Vec<BodyToken<'a>>, params: &Option<Vec<TemplateParameter>>, args: &Option<Vec<String>>, va_args: bool) -> Result<(Vec<BodyStatement>, Vec<Range<usize>>), ParseError>
 {
 
     let mut tmp = String::new();
+    let mut tmp_span = lex.span().end..lex.span().end;
     let mut res = Vec::new();
+    let mut spans = Vec::new();
 
     let mut i = 0;
 
     while i<tokens.len() {
         let t = &tokens[i];
+        if tmp.is_empty() {
+            tmp_span = t.2.start..t.2.start;
+        }
+
         match t.0 {
             Some(Ok(CodeToken::VaArgs)) => tmp.push_str(if va_args {"__VA_ARGS__"} else {t.1}),
+            // a comment could span lines, the preprocessor sees it as a single space anyway
+            Some(Ok(CodeToken::BlockComment)) => tmp.push(' '),
+            Some(Ok(CodeToken::Modifier)) => {
+                // flush buffer
+                res.push(BodyStatement::Expand(tmp));
+                spans.push(tmp_span.clone());
+                tmp = String::new();
+
+                res.push(parse_body_directive(lex, &tokens, &mut i)?);
+                spans.push(t.2.start..tokens[i].2.end);
+            },
             Some(Ok(CodeToken::Name))=> {
 
                 // flush buffer
                 res.push(BodyStatement::Expand(tmp));
+                spans.push(tmp_span.clone());
                 tmp = String::new();
 
                 if let Some(vec) = &params {
                     if let Some(index) = vec.iter().position(|e| *e == TemplateParameter::Param(String::from(t.1))) {
                         res.push(BodyStatement::Parameter(index));
+                        spans.push(t.2.clone());
                         i+=1;
                         continue;
                     }
                 }
 
-                match parse_mmgx_object_body(&tokens, &mut i, params, args) {
+                match parse_mmgx_object_body(lex, &tokens, &mut i, params, args) {
                     Some(e) => {
                         res.push(e)
                     },
                     None => res.push(BodyStatement::OptDependency(String::from(t.1)))
                 }
+                spans.push(t.2.start..tokens[i].2.end);
             },
             _ => tmp.push_str(t.1)
         }
+        tmp_span.end = tokens[i].2.end;
         i+=1;
     };
+    if tmp.is_empty() {
+        let end = tokens.last().map_or(tmp_span.end, |t| t.2.end);
+        tmp_span = end..end;
+    }
     res.push(BodyStatement::Expand(tmp));
-    res
+    spans.push(tmp_span);
+    Ok((res, spans))
+}
+
+// Collects the lines up to the matching '}', line breaks become '\' continuations
+fn parse_mmgx_block<'a>(lex: &mut logos::Lexer<'a, CodeToken>) -> Result<Vec<BodyToken<'a>>, ParseError>
+{
+    let mut tokens: Vec<BodyToken> = vec![(Some(Ok(CodeToken::LineContinuation)), " \\\n", lex.span())];
+    let mut depth = 0;
+
+    loop {
+        let t = lex_next(lex);
+        match t {
+            Some(Ok(CodeToken::CurlyOpen)) => depth+=1,
+            Some(Ok(CodeToken::CurleyClose)) => {
+                if depth == 0 {
+                    break;
+                }
+                depth-=1;
+            },
+            // a line comment would swallow the continuation
+            Some(Ok(CodeToken::Comment)) => {continue;},
+            None => {return Err(ParseError::unexpected_token(lex, None, vec![CodeToken::CurleyClose]));},
+            _ => {}
+        }
+        if t == Some(Ok(CodeToken::NewLine)) {
+            while matches!(tokens.last(), Some((Some(Ok(CodeToken::Whitespace | CodeToken::BlockComment)), _, _))) {
+                tokens.pop();
+            }
+        }
+        tokens.push((t, lex.slice(), lex.span()));
+    }
+
+    // the closing brace has to end the line
+    match next_non_whitespace(lex) {
+        Some(Ok(CodeToken::NewLine)) | None => {},
+        t => {return Err(ParseError::unexpected_token(lex, t, vec![CodeToken::NewLine]));}
+    }
+
+    while matches!(tokens.last(), Some((Some(Ok(CodeToken::Whitespace | CodeToken::NewLine | CodeToken::LineContinuation)), _, _))) {
+        tokens.pop();
+    }
+
+    Ok(tokens.into_iter()
+        .map(|t| match t.0 {
+            Some(Ok(CodeToken::NewLine)) => (Some(Ok(CodeToken::LineContinuation)), " \\\n", t.2),
+            _ => t
+        })
+        .collect())
 }
 
 fn parse_mmgx_object(lex: &mut logos::Lexer<CodeToken>) -> Result<Statement, ParseError>
 {
     let name = String::from(lex.slice());
+    let start = lex.span().start;
+    let line = lex.extras.line;
     let mut params = None;
     let mut args = None;
 
     let mut body = Vec::new();
+    let mut spans = Vec::new();
 
     let mut va_args = false;
 
@@ -523,87 +803,217 @@ fn parse_mmgx_object(lex: &mut logos::Lexer<CodeToken>) -> Result<Statement, Par
 
     let mut skip_body = false;
 
-    match lex_next(lex) {
-        Some(Ok(CodeToken::DiamondOpen)) => {
-            let list = parse_mmgx_parameters(lex, [[CodeToken::Name]], |name| Ok(String::from(name[0])), CodeToken::DiamondClose)?;
+    let mut t = lex_next(lex);
+    if t == Some(Ok(CodeToken::DiamondOpen)) {
+        let list = parse_mmgx_parameters(lex, [[CodeToken::Name]], |name| Ok(String::from(name[0])), CodeToken::DiamondClose)?;
 
-            let mut res = Vec::new();
+        let mut res = Vec::new();
 
-            for p in list {
-                if let Some(index) = res.iter().position(|e| match e{TemplateParameter::Param(name) => name==&p, _=> false}) {
-                    res.push(TemplateParameter::Reference(index));
-                } else {
-                    res.push(TemplateParameter::Param(p));
-                }
+        for p in list {
+            if let Some(index) = res.iter().position(|e| match e{TemplateParameter::Param(name) => name==&p, _=> false}) {
+                res.push(TemplateParameter::Reference(index));
+            } else {
+                res.push(TemplateParameter::Param(p));
             }
-            params = Some(res);
-        },
-        Some(Ok(CodeToken::ParenOpen)) => {
-            let list = parse_mmgx_parameters(lex, [[CodeToken::Name], [CodeToken::VaArgs]], |name| Ok(String::from(name[0])), CodeToken::ParenClose)?;
-
-            va_args = list.contains(&String::from("..."));
-
-            // feed back args
-            body.push(BodyStatement::Expand(String::from("(")));
-            body.push(BodyStatement::Expand(list.join(", ") + ")"));
+        }
+        params = Some(res);
+        t = lex_next(lex);
+    }
+    if t == Some(Ok(CodeToken::ParenOpen)) {
+        let open = lex.span();
+        let list = parse_mmgx_parameters(lex, [[CodeToken::Name], [CodeToken::VaArgs]], |name| Ok(String::from(name[0])), CodeToken::ParenClose)?;
 
-            args = Some(list);
-        },
-        Some(Ok(CodeToken::NewLine)) => skip_body=true,
-        t @ _ => body_tokens.push((t, lex.slice()))
-    };
-    match lex_next(lex) {
-        Some(Ok(CodeToken::ParenOpen)) => {
-            let args = parse_mmgx_parameters(lex, [[CodeToken::Name], [CodeToken::VaArgs]], |name| Ok(String::from(name[0])), CodeToken::ParenClose)?;
+        va_args = list.contains(&String::from("..."));
 
-            va_args = args.contains(&String::from("..."));
+        // feed back args
+        body.push(BodyStatement::Expand(String::from("(")));
+        body.push(BodyStatement::Expand(list.join(", ") + ")"));
+        spans.push(open.clone());
+        spans.push(open.end..lex.span().end);
 
-            // feed back args
-            body.push(BodyStatement::Expand(String::from("(")));
-            body.push(BodyStatement::Expand(args.join(", ") + ")"));
-        },
+        args = Some(list);
+        t = lex_next(lex);
+    }
+    match t {
         Some(Ok(CodeToken::NewLine)) => skip_body=true,
-        t @ _ => body_tokens.push((t, lex.slice()))
+        // the gap in front of the body may be padded for alignment
+        Some(Ok(CodeToken::Whitespace)) => body_tokens.push((Some(Ok(CodeToken::Whitespace)), " ", lex.span())),
+        t => body_tokens.push((t, lex.slice(), lex.span()))
     };
 
-    if !skip_body {
+    if skip_body {
+        // keeps the body range behind the definition
+        body_tokens.clear();
+    } else {
         while let Some(t) = lex_next(lex) {
             match t {
                 Ok(CodeToken::NewLine) => break,
-                _ => body_tokens.push((Some(t), lex.slice()))
+                _ => body_tokens.push((Some(t), lex.slice(), lex.span()))
             };
         };
+
+        // a '{' ending the line behind the arguments opens a multi-line body,
+        // without arguments 'BEGIN {' stays a macro expanding to '{'
+        let mut line = body_tokens.iter().filter(|t| !matches!(t.0, Some(Ok(CodeToken::Whitespace | CodeToken::Comment | CodeToken::BlockComment))));
+        if args.is_some() && matches!(line.next(), Some((Some(Ok(CodeToken::CurlyOpen)), _, _))) && line.next().is_none() {
+            body_tokens = parse_mmgx_block(lex)?;
+        }
     }
-    body.append(&mut parse_body(body_tokens, &params, &args, va_args));
-    let obj = Object {name, body};
+    let (mut statements, mut statement_spans) = parse_body(lex, body_tokens, &params, &args, va_args)?;
+    body.append(&mut statements);
+    spans.append(&mut statement_spans);
+
+    // the line break ending the definition is not part of it
+    let end = if lex.slice().ends_with('\n') {lex.span().start} else {lex.span().end};
+    let obj = Object {name, args, body, line, span: start..end, spans};
     match params {
         Some(params) => Ok(Statement::Template(Template { params, obj })),
         None => Ok(Statement::Object(obj))
     }
 }
 
-fn parse_mmgx_impl(lex: &mut logos::Lexer<CodeToken>) -> Result<CommandImpl, ParseError>
+// [START]..[END] [step STEP], [START]..<[END] [step STEP] or {[VALUE], ...}
+fn parse_value_set<'a>(lex: &logos::Lexer<'a, CodeToken>, tokens: &[BodyToken<'a>], i: &mut usize) -> Result<ValueSet, ParseError>
 {
-    match next_non_whitespace(lex) {
-        Some(Ok(CodeToken::Name)) => {
+    skip_whitespace(tokens, i);
+    if matches!(tokens.get(*i), Some((Some(Ok(CodeToken::CurlyOpen)), _, _))) {
+        let mut list = Vec::new();
+        loop {
+            *i+=1;
+            list.push(expr::parse_expr(lex, tokens, i, true)?);
+            skip_whitespace(tokens, i);
+            match tokens.get(*i).map(|t| &t.0) {
+                Some(Some(Ok(CodeToken::Comma))) => {},
+                Some(Some(Ok(CodeToken::CurleyClose))) => {
+                    *i+=1;
+                    return Ok(ValueSet::List(list));
+                },
+                _ => {return Err(unexpected_at(lex, tokens, *i, vec![CodeToken::Comma, CodeToken::CurleyClose]));}
+            }
+        }
+    }
 
-            let template = lex.slice();
-            let token = lex_next(lex);
-            if token == Some(Ok(CodeToken::DiamondOpen)) {
-                let params = parse_mmgx_parameters(lex, [[CodeToken::Number, CodeToken::Range, CodeToken::Number]], |tokens| Ok( tokens[0].parse::<usize>().unwrap() ..= tokens[2].parse().unwrap() ), CodeToken::DiamondClose)?;
+    let start = expr::parse_expr(lex, tokens, i, false)?;
+    skip_whitespace(tokens, i);
+    if !matches!(tokens.get(*i), Some((Some(Ok(CodeToken::Range)), _, _))) {
+        return Err(unexpected_at(lex, tokens, *i, vec![CodeToken::Range]));
+    }
+    *i+=1;
 
-                match lex_next(lex) {
-                    Some(Ok(CodeToken::NewLine)) => Ok(CommandImpl{template: String::from(template), params}),
-                    t @ _ => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Name]))
-                }
-            } else {
-                Err(ParseError::unexpected_token(lex, token, vec![CodeToken::DiamondOpen]))
-            }
+    let inclusive = !matches!(tokens.get(*i), Some((Some(Ok(CodeToken::DiamondOpen)), _, _)));
+    if !inclusive {
+        *i+=1;
+    }
+    let end = expr::parse_expr(lex, tokens, i, false)?;
+
+    skip_whitespace(tokens, i);
+    let step = match tokens.get(*i) {
+        Some((Some(Ok(CodeToken::Name)), "step", _)) => {
+            *i+=1;
+            Some(expr::parse_expr(lex, tokens, i, false)?)
+        },
+        _ => None
+    };
+
+    Ok(ValueSet::Range { start, end, inclusive, step })
+}
+
+fn parse_mmgx_impl<'a>(lex: &mut logos::Lexer<'a, CodeToken>) -> Result<CommandImpl, ParseError>
+{
+    let tokens = collect_line(lex);
+    let mut i = 0;
+
+    skip_whitespace(&tokens, &mut i);
+    let template = match tokens.get(i) {
+        Some((Some(Ok(CodeToken::Name)), name, _)) => String::from(*name),
+        _ => {return Err(unexpected_at(lex, &tokens, i, vec![CodeToken::Name]));}
+    };
+
+    i+=1;
+    if !matches!(tokens.get(i), Some((Some(Ok(CodeToken::DiamondOpen)), _, _))) {
+        return Err(unexpected_at(lex, &tokens, i, vec![CodeToken::DiamondOpen]));
+    }
+
+    let mut params = Vec::new();
+    loop {
+        i+=1;
+        params.push(parse_value_set(lex, &tokens, &mut i)?);
+        skip_whitespace(&tokens, &mut i);
+        match tokens.get(i).map(|t| &t.0) {
+            Some(Some(Ok(CodeToken::Comma))) => {},
+            Some(Some(Ok(CodeToken::DiamondClose))) => break,
+            _ => {return Err(unexpected_at(lex, &tokens, i, vec![CodeToken::Comma, CodeToken::DiamondClose]));}
+        }
+    }
+
+    i+=1;
+    skip_whitespace(&tokens, &mut i);
+
+    // where [CONDITION]
+    let condition = match tokens.get(i) {
+        Some((Some(Ok(CodeToken::Name)), "where", _)) => {
+            i+=1;
+            Some(expr::parse_expr(lex, &tokens, &mut i, true)?)
         },
-        t @ _ => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Name]))
+        _ => None
+    };
+
+    skip_whitespace(&tokens, &mut i);
+    if i < tokens.len() {
+        return Err(unexpected_at(lex, &tokens, i, vec![CodeToken::NewLine]));
     }
 
+    Ok(CommandImpl{template, params, condition})
 }
+
+// [NAME] = [VALUE]
+fn parse_mmgx_let<'a>(lex: &mut logos::Lexer<'a, CodeToken>) -> Result<CommandLet, ParseError>
+{
+    let tokens = collect_line(lex);
+    let mut i = 0;
+
+    skip_whitespace(&tokens, &mut i);
+    let name = match tokens.get(i) {
+        Some((Some(Ok(CodeToken::Name | CodeToken::Identifier)), name, _)) => String::from(*name),
+        _ => {return Err(unexpected_at(lex, &tokens, i, vec![CodeToken::Name]));}
+    };
+
+    i+=1;
+    skip_whitespace(&tokens, &mut i);
+    if !matches!(tokens.get(i), Some((Some(Ok(CodeToken::Assign)), _, _))) {
+        return Err(unexpected_at(lex, &tokens, i, vec![CodeToken::Assign]));
+    }
+
+    i+=1;
+    let value = expr::parse_expr(lex, &tokens, &mut i, true)?;
+
+    skip_whitespace(&tokens, &mut i);
+    if i < tokens.len() {
+        return Err(unexpected_at(lex, &tokens, i, vec![CodeToken::NewLine]));
+    }
+
+    Ok(CommandLet{name, value})
+}
+
+// [EXPRESSION] => [EXPECTED], both sides are kept as C code
+fn parse_mmgx_test<'a>(lex: &mut logos::Lexer<'a, CodeToken>) -> Result<CommandTest, ParseError>
+{
+    let tokens = collect_line(lex);
+
+    let arrow = match tokens.windows(2).position(|t| t[0].0 == Some(Ok(CodeToken::Assign)) && t[1].0 == Some(Ok(CodeToken::DiamondClose))) {
+        Some(arrow) => arrow,
+        None => {return Err(unexpected_at(lex, &tokens, tokens.len(), vec![CodeToken::Assign]));}
+    };
+
+    let text = |tokens: &[BodyToken]| String::from(tokens.iter().map(|t| t.1).collect::<String>().trim());
+    let expression = text(&tokens[..arrow]);
+    if expression.is_empty() {
+        return Err(unexpected_at(lex, &tokens, arrow, vec![CodeToken::Name]));
+    }
+
+    Ok(CommandTest{expression, expected: text(&tokens[arrow+2..])})
+}
+
 fn parse_mmgx_command(lex: &mut logos::Lexer<CodeToken>) -> Result<Statement, ParseError>
 {
     match lex_next(lex) {
@@ -617,60 +1027,130 @@ fn parse_mmgx_command(lex: &mut logos::Lexer<CodeToken>) -> Result<Statement, Pa
 
                     match parse_mmgx_object(lex)? {
                         Statement::Object(obj) => Ok(Statement::Command(Command::Export(obj))),
-                        t @ _ => Err(ParseError::wrong_argument(lex, position, String::from("@export"), format!("{:?}", t), vec![String::from("Object")]))
+                        t => Err(ParseError::wrong_argument(lex, position, String::from("@export"), format!("{:?}", t), vec![String::from("Object")]))
                     }
                 },
                 "use" => {
                     match next_non_whitespace(lex) {
                         Some(Ok(CodeToken::Identifier)) | Some(Ok(CodeToken::Name)) => Ok(Statement::Command(Command::Use(String::from(lex.slice())))),
-                        t @ _ => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Identifier, CodeToken::Name]))
+                        t => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Identifier, CodeToken::Name]))
                     }
 
                 }
+                "import" => {
+                    match next_non_whitespace(lex) {
+                        Some(Ok(CodeToken::String)) => {
+                            let path = lex.slice();
+                            Ok(Statement::Command(Command::Import(String::from(&path[1..path.len()-1]))))
+                        },
+                        t => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::String]))
+                    }
+                },
                 "impl" => Ok(Statement::Command(Command::Impl(parse_mmgx_impl(lex)?))),
+                "let" => Ok(Statement::Command(Command::Let(parse_mmgx_let(lex)?))),
+                "test" => Ok(Statement::Command(Command::Test(parse_mmgx_test(lex)?))),
                 _ => Err(ParseError::unknown_command(lex))
             }
         },
-        t @ _ => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Name]))
+        t => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Name]))
     }
 }
 
-fn parse_mmgx_body(lex: &mut logos::Lexer<CodeToken>) -> Result<Vec<Statement>, ParseError>
+// Skips the rest of a broken statement, false if the module or the file ended on the way
+fn recover(lex: &mut logos::Lexer<CodeToken>) -> bool
 {
-    let mut statements = Vec::new();
+    match lex.slice() {
+        "\n" | "\r\n" => {return true;},
+        // a '}' opening its line closes the module
+        "}" if lex.source()[..lex.span().start].rsplit('\n').next().is_some_and(|l| l.trim().is_empty()) => {return false;},
+        _ => {}
+    }
     loop {
         match lex_next(lex) {
-            Some(Ok(CodeToken::Name)) => statements.push(parse_mmgx_object(lex)?),
-            Some(Ok(CodeToken::Modifier)) => {
-                statements.push(parse_mmgx_command(lex)?);
-            },
-            Some(Ok(CodeToken::CurleyClose)) => {return Ok(statements);},
+            Some(Ok(CodeToken::NewLine)) => {return true;},
+            None => {return false;},
+            _ => {}
+        }
+    }
+}
+
+// The statements of a module, each with its source range
+fn parse_mmgx_body(lex: &mut logos::Lexer<CodeToken>, errors: &mut Vec<ParseError>) -> (Vec<Statement>, Vec<Range<usize>>)
+{
+    let mut statements = Vec::new();
+    let mut spans = Vec::new();
+    loop {
+        let token = lex_next(lex);
+        let start = lex.span().start;
+        let statement = match token {
+            Some(Ok(CodeToken::Name)) | Some(Ok(CodeToken::Identifier)) => parse_mmgx_object(lex),
+            Some(Ok(CodeToken::Modifier)) => parse_mmgx_command(lex),
+            Some(Ok(CodeToken::CurleyClose)) => {return (statements, spans);},
             Some(Ok(CodeToken::Whitespace)) |
             Some(Ok(CodeToken::NewLine)) => continue,
-            Some(Ok(CodeToken::Comment)) => statements.push(Statement::Comment(String::from(lex.slice()))),
-            t @ _ => {return Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Name, CodeToken::Modifier, CodeToken::CurleyClose]));}
+            Some(Ok(CodeToken::Comment)) |
+            Some(Ok(CodeToken::BlockComment)) => Ok(Statement::Comment(String::from(lex.slice()))),
+            None => {
+                errors.push(ParseError::unexpected_token(lex, None, vec![CodeToken::Name, CodeToken::Modifier, CodeToken::CurleyClose]));
+                return (statements, spans);
+            },
+            t => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Name, CodeToken::Modifier, CodeToken::CurleyClose]))
+        };
+
+        match statement {
+            Ok(statement) => {
+                statements.push(statement);
+                // the line break ending the statement is not part of it
+                spans.push(start..if lex.slice().ends_with('\n') {lex.span().start} else {lex.span().end});
+            },
+            Err(err) => {
+                errors.push(err);
+                if !recover(lex) {
+                    return (statements, spans);
+                }
+            }
         }
     }
 }
 
-fn parse_mmgx_module(lex: &mut logos::Lexer<CodeToken>) -> Result<MmgxModule, ParseError>
+fn parse_mmgx_module(lex: &mut logos::Lexer<CodeToken>, errors: &mut Vec<ParseError>) -> Result<MmgxModule, ParseError>
 {
+    let start = lex.span().start;
+    let first_line = lex.extras.line;
     match next_non_whitespace(lex) {
         Some(Ok(CodeToken::Identifier)) | Some(Ok(CodeToken::Name)) =>{
             let name = lex.slice();
             let token = next_non_whitespace(lex);
             if token == Some(Ok(CodeToken::CurlyOpen)) {
 
-                Ok(MmgxModule{name: String::from(name), body: parse_mmgx_body(lex)?, line: lex.extras.line})
+                let (body, spans) = parse_mmgx_body(lex, errors);
+                let span = start..lex.span().end;
+                let line_start = lex.source()[..start].rfind('\n').map_or(0, |i| i+1);
+                let line_starts = std::iter::once(line_start)
+                    .chain(lex.source()[span.clone()].match_indices('\n').map(|(i, _)| start+i+1))
+                    .collect();
+                Ok(MmgxModule{name: String::from(name), body, line: lex.extras.line, span, spans, first_line, line_starts})
 
             }else{
                 Err(ParseError::unexpected_token(lex, token, vec![CodeToken::CurlyOpen]))
             }
         },
-        t @ _ => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Identifier]))
+        t => Err(ParseError::unexpected_token(lex, t, vec![CodeToken::Identifier]))
     }
 }
 
+fn starts_module(lex: &logos::Lexer<CodeToken>) -> bool
+{
+    let mut lookahead = lex.clone();
+    matches!(lex_next(&mut lookahead), Some(Ok(CodeToken::Identifier | CodeToken::Name)))
+        && next_non_whitespace(&mut lookahead) == Some(Ok(CodeToken::CurlyOpen))
+}
+
+/// Pseudo path of a document read from stdin, used in diagnostics
+pub const STDIN_PATH: &str = "<stdin>";
+/// Pseudo path of a document parsed from memory
+pub const STRING_PATH: &str = "<string>";
+
 pub fn parse_x_file(path: &PathBuf) -> Result<Vec<Section>, MmgxError>
 {
     let source = match  read_to_string(path) {
@@ -678,22 +1158,235 @@ pub fn parse_x_file(path: &PathBuf) -> Result<Vec<Section>, MmgxError>
         Err(e) =>{ return Err(MmgxError::file_read_error(path, e));}
     };
 
-    let mut lex = CodeToken::lexer_with_extras(source.as_str(), LexerInfo {line: 0, line_start: 0, line_start_last: 0, path: path.into(), offset: 0});
+    parse_x_source(path, &source)
+}
+
+pub fn parse_x_stdin() -> Result<Vec<Section>, MmgxError>
+{
+    let path = Path::new(STDIN_PATH);
+    let mut source = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut source) {
+        return Err(MmgxError::file_read_error(path, e));
+    }
+
+    parse_x_source(path, &source)
+}
+
+/// Parses a document held in memory
+pub fn parse_str(source: &str) -> Result<Vec<Section>, MmgxError>
+{
+    parse_source(STRING_PATH, source)
+}
+
+/// Parses a document held in memory, diagnostics refer to it as `name`
+pub fn parse_source(name: &str, source: &str) -> Result<Vec<Section>, MmgxError>
+{
+    parse_x_source(Path::new(name), source)
+}
+
+pub(crate) fn parse_x_source(path: &Path, source: &str) -> Result<Vec<Section>, MmgxError>
+{
+    let mut lex = CodeToken::lexer_with_extras(source, LexerInfo {line: 0, path: path.into(), offset: 0});
     let mut file = Vec::new();
 
+    // modules only start with '@[NAME] {' as the first thing on a line
+    let mut line_start = true;
+    let mut errors = Vec::new();
+
     while let Some(token) = lex_next(&mut lex) {
         match token {
-            Ok(CodeToken::Modifier) =>{
-                file.push(Section::MmgxModule(Rc::new(parse_mmgx_module(&mut lex)
-                        .map_err(|error| MmgxError::parse_error(&lex.extras.path, error))?)))
+            Ok(CodeToken::Modifier) if line_start && starts_module(&lex) =>{
+                match parse_mmgx_module(&mut lex, &mut errors) {
+                    Ok(module) => file.push(Section::MmgxModule(Rc::new(module))),
+                    Err(err) => errors.push(err)
+                }
+                line_start = false;
             },
             _ =>{
+                let mut text = lex.slice();
+
+                // '@@' escapes a literal '@'
+                if token == Ok(CodeToken::Modifier) && lex.remainder().starts_with('@') {
+                    lex_next(&mut lex);
+                    text = "@";
+                }
+
+                match token {
+                    Ok(CodeToken::NewLine) => line_start = true,
+                    Ok(CodeToken::Whitespace | CodeToken::Comment | CodeToken::BlockComment) => {},
+                    _ => line_start = false
+                }
+
                 match file.last_mut() {
-                    Some(Section::CSource(string)) => string.push_str(lex.slice()),
-                    _ => file.push(Section::CSource(String::from(lex.slice())))
+                    Some(Section::CSource(string, span)) => {
+                        string.push_str(text);
+                        span.end = lex.span().end;
+                    },
+                    _ => file.push(Section::CSource(String::from(text), lex.span()))
                 };
             }
         }
     };
-    Ok(file)
+
+    if errors.is_empty() {
+        Ok(file)
+    } else {
+        let mut res = MmgxError::new();
+        for error in errors {
+            res.append(MmgxError::parse_error(&lex.extras.path, error));
+        }
+        Err(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::MmgxErrorKind;
+
+    #[test]
+    fn error_marks_after_multibyte_characters()
+    {
+        let err = parse_source("u.x", "@U {\n\tA é @bogus\n}\n").unwrap_err();
+        let text = err.to_string();
+        assert!(text.contains("Unknown Command 'bogus'"));
+        assert!(text.contains("\tA é @bogus\n          ~~~~~"));
+    }
+
+    fn tokens(text: &str) -> Vec<(Result<CodeToken, ()>, &str)>
+    {
+        let mut lex = lexer(text);
+        let mut res = Vec::new();
+        while let Some(token) = lex.next() {
+            res.push((token, lex.slice()));
+        }
+        res
+    }
+
+    #[test]
+    fn string_and_char_literals()
+    {
+        assert_eq!(tokens(r#""a \"b\" <c>""#), [(Ok(CodeToken::String), r#""a \"b\" <c>""#)]);
+        assert_eq!(tokens(r#""\\" x"#)[0], (Ok(CodeToken::String), r#""\\""#));
+        assert_eq!(tokens(r"'\'' '{' '<'"), [
+            (Ok(CodeToken::Char), r"'\''"),
+            (Ok(CodeToken::Whitespace), " "),
+            (Ok(CodeToken::Char), "'{'"),
+            (Ok(CodeToken::Whitespace), " "),
+            (Ok(CodeToken::Char), "'<'")
+        ]);
+        // literals end at the line
+        assert_ne!(tokens("\"a\nb\"")[0].0, Ok(CodeToken::String));
+    }
+
+    #[test]
+    fn block_comments_count_their_lines()
+    {
+        let mut lex = lexer("/* a\n * \"b\n */x\n// c */\ny");
+        assert_eq!(lex.next(), Some(Ok(CodeToken::BlockComment)));
+        assert_eq!(lex.slice(), "/* a\n * \"b\n */");
+        assert_eq!(lex.extras.line, 2);
+        let rest: Vec<CodeToken> = lex.by_ref().map(|token| token.unwrap()).collect();
+        assert_eq!(rest, [CodeToken::Name, CodeToken::NewLine, CodeToken::Comment, CodeToken::NewLine, CodeToken::Name]);
+        assert_eq!(lex.extras.line, 4);
+        // an open comment is no comment
+        assert_ne!(tokens("/* a")[0].0, Ok(CodeToken::BlockComment));
+    }
+
+    #[test]
+    fn literals_in_modules()
+    {
+        let sections = parse_source("l.x", "@L {\n\tS(a) f(\"a,b)\", '{', a) /* } */\n\tT \"<x>\" '>'\n\t/* two\n\t lines */\n\tU 1\n}\n").unwrap();
+        let Section::MmgxModule(module) = &sections[0] else { panic!("no module") };
+        let objects: Vec<(&str, usize)> = module.body.iter().filter_map(|statement| match statement {
+            Statement::Object(obj) => Some((obj.name.as_str(), obj.line)),
+            _ => None
+        }).collect();
+        assert_eq!(objects, [("S", 1), ("T", 2), ("U", 5)]);
+    }
+
+    // the C code of a document and the names of its modules
+    fn split(source: &str) -> (String, Vec<String>)
+    {
+        let mut c = String::new();
+        let mut modules = Vec::new();
+        for section in parse_source("s.x", source).unwrap() {
+            match section {
+                Section::CSource(text, _) => c += &text,
+                Section::MmgxModule(module) => modules.push(module.name.clone())
+            }
+        }
+        (c, modules)
+    }
+
+    #[test]
+    fn modules_start_at_the_line_start()
+    {
+        let (c, modules) = split("  @A {\n}\nint a @B {};\n/* x */ @C {\n}\n");
+        assert_eq!(modules, ["A", "C"]);
+        assert_eq!(c, "  \nint a @B {};\n/* x */ \n");
+
+        // at-signs in comments, strings and doxygen tags stay C
+        let source = "/** @param x {}\n */\n// @D {\nchar *s = \"@E {\";\nint f(int x); @F\n";
+        assert_eq!(split(source), (String::from(source), Vec::new()));
+    }
+
+    #[test]
+    fn escaped_at_signs()
+    {
+        let (c, modules) = split("@@A {\n}\nx @@ y @@@B\n");
+        assert!(modules.is_empty());
+        assert_eq!(c, "@A {\n}\nx @ y @@B\n");
+    }
+
+    fn tests(source: &str) -> Vec<(String, String)>
+    {
+        let sections = parse_source("t.x", source).unwrap();
+        let module = sections.iter().find_map(|section| match section {
+            Section::MmgxModule(module) => Some(module),
+            _ => None
+        }).unwrap();
+        module.body.iter().filter_map(|statement| match statement {
+            Statement::Command(Command::Test(test)) => Some((test.expression.clone(), test.expected.clone())),
+            _ => None
+        }).collect()
+    }
+
+    #[test]
+    fn test_commands()
+    {
+        let parsed = tests("@T {\n\t@test F(1, 2) => a + b\n\t@test A>=B=>C\n\t@test E =>\n}\n");
+        let expected = [("F(1, 2)", "a + b"), ("A>=B", "C"), ("E", "")];
+        assert_eq!(parsed, expected.map(|(e, x)| (String::from(e), String::from(x))));
+
+        let err = parse_source("t.x", "@T {\n\t@test A = > B\n}\n").unwrap_err().to_string();
+        assert!(err.contains("error at t.x:2:15 Reached End of File expected Token [Assign]"), "{}", err);
+        let err = parse_source("t.x", "@T {\n\t@test => B\n}\n").unwrap_err().to_string();
+        assert!(err.contains("error at t.x:2:8 Unexpected Token got Assign"), "{}", err);
+    }
+
+    #[test]
+    fn unclosed_lists_point_at_their_opening()
+    {
+        let err = parse_source("u.x", "@M {\n\tX<A\n\tY 1\n\tF(a, b\n\tZ<N> N\n}\nint x;\n").unwrap_err();
+        let positions: Vec<(usize, usize)> = err.diagnostics().iter().map(|diagnostic| match diagnostic.kind() {
+            MmgxErrorKind::ParseError(e) => {
+                assert!(matches!(e.kind(), ParseErrorKind::Unclosed(_)), "{}", err);
+                (e.line(), e.column())
+            },
+            kind => panic!("{:?}", kind)
+        }).collect();
+        // the statements behind them are still parsed
+        assert_eq!(positions, [(1, 2), (3, 2)]);
+        assert!(err.to_string().contains("error at u.x:2:3 Unclosed list expected Token DiamondClose"), "{}", err);
+    }
+
+    #[test]
+    fn error_after_line_continuation()
+    {
+        let err = parse_source("c.x", "@M {\n\t@use \\\n}\n").unwrap_err();
+        let text = err.to_string();
+        assert!(text.contains("error at c.x:2:7 Unexpected Token got LineContinuation"), "{}", text);
+        assert!(text.contains("\t@use \\\n\n          ~~"), "{}", text);
+    }
 }
@@ -0,0 +1,223 @@
+use super::{BodyToken, CodeToken, ParseError, skip_whitespace, unexpected_at};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Negate,
+    Not
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>)
+}
+
+struct ExprParser<'a, 'b> {
+    lex: &'b logos::Lexer<'a, CodeToken>,
+    tokens: &'b [BodyToken<'a>],
+    index: usize,
+    // '<' and '>' close template argument lists, so they only compare inside parentheses there
+    relational: bool
+}
+
+impl<'a, 'b> ExprParser<'a, 'b> {
+
+    fn peek(&mut self) -> Option<&'b BodyToken<'a>>
+    {
+        skip_whitespace(self.tokens, &mut self.index);
+        self.tokens.get(self.index)
+    }
+
+    fn unexpected(&self, expected: Vec<CodeToken>) -> ParseError
+    {
+        unexpected_at(self.lex, self.tokens, self.index, expected)
+    }
+
+    fn binary_op(&self, token: &Option<Result<CodeToken, ()>>) -> Option<(BinaryOp, u8)>
+    {
+        match token.as_ref()?.as_ref().ok()? {
+            CodeToken::Or => Some((BinaryOp::Or, 1)),
+            CodeToken::And => Some((BinaryOp::And, 2)),
+            CodeToken::Equal => Some((BinaryOp::Equal, 3)),
+            CodeToken::NotEqual => Some((BinaryOp::NotEqual, 3)),
+            CodeToken::DiamondOpen if self.relational => Some((BinaryOp::Less, 4)),
+            CodeToken::DiamondClose if self.relational => Some((BinaryOp::Greater, 4)),
+            CodeToken::LessEqual if self.relational => Some((BinaryOp::LessEqual, 4)),
+            CodeToken::GreaterEqual if self.relational => Some((BinaryOp::GreaterEqual, 4)),
+            CodeToken::Plus => Some((BinaryOp::Add, 5)),
+            CodeToken::Minus => Some((BinaryOp::Sub, 5)),
+            CodeToken::Star => Some((BinaryOp::Mul, 6)),
+            CodeToken::Slash => Some((BinaryOp::Div, 6)),
+            CodeToken::Percent => Some((BinaryOp::Rem, 6)),
+            _ => None
+        }
+    }
+
+    fn parse_binary(&mut self, min: u8) -> Result<Expr, ParseError>
+    {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some((op, precedence)) = self.peek().and_then(|t| self.binary_op(&t.0)) {
+            if precedence < min {
+                break;
+            }
+            self.index+=1;
+            let rhs = self.parse_binary(precedence+1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError>
+    {
+        let op = match self.peek().map(|t| &t.0) {
+            Some(Some(Ok(CodeToken::Minus))) => UnaryOp::Negate,
+            Some(Some(Ok(CodeToken::Not))) => UnaryOp::Not,
+            _ => {return self.parse_primary();}
+        };
+        self.index+=1;
+        Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError>
+    {
+        let expected = vec![CodeToken::Number, CodeToken::Name, CodeToken::ParenOpen, CodeToken::Minus, CodeToken::Not];
+
+        let token = match self.peek() {
+            Some(t) => t,
+            None => {return Err(self.unexpected(expected));}
+        };
+
+        match token.0 {
+            Some(Ok(CodeToken::Number)) => {
+                match token.1.parse() {
+                    Ok(num) => {
+                        self.index+=1;
+                        Ok(Expr::Number(num))
+                    },
+                    Err(_) => Err(self.unexpected(expected))
+                }
+            },
+            Some(Ok(CodeToken::Name)) | Some(Ok(CodeToken::Identifier)) => {
+                self.index+=1;
+                Ok(Expr::Variable(String::from(token.1)))
+            },
+            Some(Ok(CodeToken::ParenOpen)) => {
+                self.index+=1;
+                let relational = self.relational;
+                self.relational = true;
+                let expr = self.parse_binary(0)?;
+                self.relational = relational;
+
+                match self.peek() {
+                    Some((Some(Ok(CodeToken::ParenClose)), _, _)) => {
+                        self.index+=1;
+                        Ok(expr)
+                    },
+                    _ => Err(self.unexpected(vec![CodeToken::ParenClose]))
+                }
+            },
+            _ => Err(self.unexpected(expected))
+        }
+    }
+}
+
+/// Parses an integer expression starting at `tokens[*index]`.
+/// On success `index` points behind the last token of the expression.
+/// With `relational` unset, `<` and `>` are only treated as comparisons inside parentheses.
+pub fn parse_expr<'a>(lex: &logos::Lexer<'a, CodeToken>, tokens: &[BodyToken<'a>], index: &mut usize, relational: bool) -> Result<Expr, ParseError>
+{
+    let mut parser = ExprParser { lex, tokens, index: *index, relational };
+    let expr = parser.parse_binary(0)?;
+    *index = parser.index;
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::lexer;
+
+    fn render(e: &Expr) -> String
+    {
+        match e {
+            Expr::Number(num) => num.to_string(),
+            Expr::Variable(name) => name.clone(),
+            Expr::Unary(op, operand) => format!("{:?}({})", op, render(operand)),
+            Expr::Binary(op, lhs, rhs) => format!("{:?}({}, {})", op, render(lhs), render(rhs))
+        }
+    }
+
+    // the parsed expression and the text left behind it
+    fn parse(text: &str, relational: bool) -> Result<(String, String), ParseError>
+    {
+        let mut lex = lexer(text);
+        let mut tokens = Vec::new();
+        while let Some(token) = lex.next() {
+            tokens.push((Some(token), lex.slice(), lex.span()));
+        }
+        let mut index = 0;
+        let expr = parse_expr(&lex, &tokens, &mut index, relational)?;
+        let rest = tokens[index..].iter().map(|t| t.1).collect::<String>();
+        Ok((render(&expr), rest))
+    }
+
+    fn parsed(text: &str, relational: bool) -> String
+    {
+        let (expr, rest) = parse(text, relational).unwrap();
+        assert_eq!(rest, "");
+        expr
+    }
+
+    #[test]
+    fn precedence()
+    {
+        assert_eq!(parsed("1 + 2 * 3", true), "Add(1, Mul(2, 3))");
+        assert_eq!(parsed("(1 + 2) * 3", true), "Mul(Add(1, 2), 3)");
+        assert_eq!(parsed("1 - 2 - 3", true), "Sub(Sub(1, 2), 3)");
+        assert_eq!(parsed("a || b && c == d", true), "Or(a, And(b, Equal(c, d)))");
+        assert_eq!(parsed("a == b < c + 1", true), "Equal(a, Less(b, Add(c, 1)))");
+        assert_eq!(parsed("-a % 4 != !b", true), "NotEqual(Rem(Negate(a), 4), Not(b))");
+    }
+
+    #[test]
+    fn relational_only_inside_parentheses()
+    {
+        assert_eq!(parsed("a < b", true), "Less(a, b)");
+        assert_eq!(parsed("a >= b", true), "GreaterEqual(a, b)");
+
+        // in template arguments '<' and '>' end the expression
+        assert_eq!(parse("A+1>", false).unwrap(), (String::from("Add(A, 1)"), String::from(">")));
+        assert_eq!(parse("A < B", false).unwrap(), (String::from("A"), String::from("< B")));
+        assert_eq!(parsed("(A < B)", false), "Less(A, B)");
+        assert_eq!(parse("(A > B) > C", false).unwrap(), (String::from("Greater(A, B)"), String::from("> C")));
+    }
+
+    #[test]
+    fn malformed()
+    {
+        assert!(parse("1 +", true).is_err());
+        assert!(parse("(1 + 2", true).is_err());
+        assert!(parse("* 2", true).is_err());
+        assert!(parse("99999999999999999999", true).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use std::ops::Range;
+use super::{lexer, BinaryOp, CodeToken, Command, Expr, MmgxModule, Object, Section, Statement, TemplateParameter, UnaryOp, ValueSet};
+
+// Canonical layout of the modules, everything outside of them is copied as is
+
+fn precedence(op: BinaryOp) -> u8
+{
+    match op {
+        BinaryOp::Or => 1,
+        BinaryOp::And => 2,
+        BinaryOp::Equal | BinaryOp::NotEqual => 3,
+        BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 4,
+        BinaryOp::Add | BinaryOp::Sub => 5,
+        BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 6
+    }
+}
+
+fn operator(op: BinaryOp) -> &'static str
+{
+    match op {
+        BinaryOp::Add => "+",
+        BinaryOp::Sub => "-",
+        BinaryOp::Mul => "*",
+        BinaryOp::Div => "/",
+        BinaryOp::Rem => "%",
+        BinaryOp::Less => "<",
+        BinaryOp::LessEqual => "<=",
+        BinaryOp::Greater => ">",
+        BinaryOp::GreaterEqual => ">=",
+        BinaryOp::Equal => "==",
+        BinaryOp::NotEqual => "!=",
+        BinaryOp::And => "&&",
+        BinaryOp::Or => "||"
+    }
+}
+
+// `relational` mirrors the parser, without it '<' and '>' only compare inside parentheses
+fn expr(e: &Expr, relational: bool) -> String
+{
+    match e {
+        Expr::Number(num) => num.to_string(),
+        Expr::Variable(name) => name.clone(),
+        Expr::Unary(op, operand) => {
+            let op = match op {
+                UnaryOp::Negate => "-",
+                UnaryOp::Not => "!"
+            };
+            match **operand {
+                Expr::Binary(..) => format!("{}({})", op, expr(operand, true)),
+                _ => format!("{}{}", op, expr(operand, relational))
+            }
+        },
+        Expr::Binary(op, lhs, rhs) => {
+            let level = precedence(*op);
+            if level == 4 && !relational {
+                return format!("({})", expr(e, true));
+            }
+            let side = |e: &Expr, paren: bool| if paren {format!("({})", expr(e, true))} else {expr(e, relational)};
+            let lhs = side(lhs, matches!(**lhs, Expr::Binary(op, ..) if precedence(op) < level));
+            let rhs = side(rhs, matches!(**rhs, Expr::Binary(op, ..) if precedence(op) <= level));
+            format!("{} {} {}", lhs, operator(*op), rhs)
+        }
+    }
+}
+
+fn value_set(set: &ValueSet) -> String
+{
+    match set {
+        ValueSet::Range { start, end, inclusive, step } => {
+            let mut res = format!("{}..{}{}", expr(start, false), if *inclusive {""} else {"<"}, expr(end, false));
+            if let Some(step) = step {
+                res += format!(" step {}", expr(step, false)).as_str();
+            }
+            res
+        },
+        ValueSet::List(list) => format!("{{{}}}", list.iter().map(|e| expr(e, true)).collect::<Vec<_>>().join(", "))
+    }
+}
+
+// Offset behind the first `end` token, comments can not end anything
+fn token_end(text: &str, end: CodeToken) -> usize
+{
+    let mut lex = lexer(text);
+    while let Some(token) = lex.next() {
+        if token == Ok(end.clone()) {
+            return lex.span().end;
+        }
+    }
+    text.len()
+}
+
+// Comments are dropped by the parser inside commands and headers, so they are picked up from the source again
+fn comments(text: &str) -> Vec<&str>
+{
+    let mut lex = lexer(text);
+    let mut res = Vec::new();
+    while let Some(token) = lex.next() {
+        if matches!(token, Ok(CodeToken::Comment | CodeToken::BlockComment)) {
+            res.push(lex.slice().trim_end());
+        }
+    }
+    res
+}
+
+fn with_comments(mut line: String, text: &str) -> String
+{
+    for comment in comments(text) {
+        line.push(' ');
+        line += comment;
+    }
+    line
+}
+
+// Comments from within the header, name, template parameters and arguments, followed by the body.
+// The header is rebuilt, so its comments go on their own lines in front of it.
+fn object(source: &str, obj: &Object, params: Option<&[TemplateParameter]>) -> (Vec<String>, String, String)
+{
+    let mut header = obj.name.clone();
+    if let Some(params) = params {
+        let names: Vec<&str> = params.iter().map(|p| match p {
+            TemplateParameter::Param(name) => name.as_str(),
+            TemplateParameter::Reference(index) => match &params[*index] {
+                TemplateParameter::Param(name) => name.as_str(),
+                TemplateParameter::Reference(_) => unreachable!()
+            }
+        }).collect();
+        header += format!("<{}>", names.join(", ")).as_str();
+    }
+    if let Some(args) = &obj.args {
+        header += format!("({})", args.join(", ")).as_str();
+    }
+
+    // the lexer takes '<...>' and '(...)' only right behind the name
+    let name_end = obj.span.start+obj.name.len();
+    let mut body_start = name_end;
+    if params.is_some() {
+        body_start += token_end(&source[body_start..obj.span.end], CodeToken::DiamondClose);
+    }
+    if obj.args.is_some() {
+        body_start += token_end(&source[body_start..obj.span.end], CodeToken::ParenClose);
+    }
+    let comments = comments(&source[name_end..body_start]).into_iter().map(String::from).collect();
+    (comments, header, block(source[body_start..obj.span.end].trim()))
+}
+
+// Lines of a multi-line body are indented one level deeper than the definition
+fn block(body: &str) -> String
+{
+    let lines: Vec<&str> = body.lines().collect();
+    if lines.len() < 3 || !lines[0].starts_with('{') || lines[lines.len()-1].trim() != "}" {
+        return String::from(body);
+    }
+
+    let inner = &lines[1..lines.len()-1];
+    let indent = inner.iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min().unwrap_or(0);
+
+    let mut res = String::from(lines[0]) + "\n";
+    for line in inner {
+        if !line.trim().is_empty() {
+            res += "\t\t";
+            res += line[indent..].trim_end();
+        }
+        res.push('\n');
+    }
+    res + "\t}"
+}
+
+enum Line {
+    Definition(Vec<String>, String, String),
+    Other(String)
+}
+
+fn statement(source: &str, statement: &Statement, at: &Range<usize>) -> Line
+{
+    let text = &source[at.clone()];
+    match statement {
+        Statement::Object(obj) => {
+            let (comments, header, body) = object(source, obj, None);
+            Line::Definition(comments, header, body)
+        },
+        Statement::Template(temp) => {
+            let (comments, header, body) = object(source, &temp.obj, Some(&temp.params));
+            Line::Definition(comments, header, body)
+        },
+        Statement::Command(Command::Export(obj)) => {
+            let (comments, header, body) = object(source, obj, None);
+            let header = with_comments(String::from("@export"), &source[at.start..obj.span.start]) + " " + header.as_str();
+            Line::Definition(comments, header, body)
+        },
+        Statement::Command(Command::Use(name)) => Line::Other(with_comments(format!("@use {}", name), text)),
+        Statement::Command(Command::Import(path)) => Line::Other(with_comments(format!("@import \"{}\"", path), text)),
+        Statement::Command(Command::Let(cmd_let)) => Line::Other(with_comments(format!("@let {} = {}", cmd_let.name, expr(&cmd_let.value, true)), text)),
+        Statement::Command(Command::Test(test)) => Line::Other(with_comments(format!("@test {} => {}", test.expression, test.expected), text)),
+        Statement::Command(Command::Impl(cmd_impl)) => {
+            let params: Vec<String> = cmd_impl.params.iter().map(value_set).collect();
+            let mut line = format!("@impl {}<{}>", cmd_impl.template, params.join(", "));
+            if let Some(condition) = &cmd_impl.condition {
+                line += format!(" where {}", expr(condition, true)).as_str();
+            }
+            Line::Other(with_comments(line, text))
+        },
+        Statement::Comment(comment) => Line::Other(String::from(comment.trim_end()))
+    }
+}
+
+fn module(source: &str, module: &MmgxModule) -> String
+{
+    let lines: Vec<Line> = module.body.iter().zip(module.spans.iter()).map(|(s, at)| statement(source, s, at)).collect();
+
+    // line breaks in front of each statement, more than one keep a single blank line
+    let breaks: Vec<usize> = module.spans.iter().enumerate().map(|(i, at)| {
+        if i == 0 {
+            return 1;
+        }
+        let gap = source[module.spans[i-1].end..at.start].matches('\n').count();
+        match (gap, &module.body[i]) {
+            (0, Statement::Comment(_)) => 0,
+            (0, _) => 1,
+            (gap, _) => gap.min(2)
+        }
+    }).collect();
+
+    // bodies of definitions on consecutive lines start in the same column, comments end a run
+    let single_line = |line: &Line| matches!(line, Line::Definition(_, _, body) if !body.contains('\n'));
+    let commented = |line: &Line| matches!(line, Line::Definition(comments, _, _) if !comments.is_empty());
+    let mut width = vec![0; lines.len()];
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = start+1;
+        if single_line(&lines[start]) {
+            while end < lines.len() && single_line(&lines[end]) && !commented(&lines[end]) && breaks[end] == 1 {
+                end+=1;
+            }
+        }
+        let max = lines[start..end].iter().filter_map(|line| match line {
+            Line::Definition(_, header, body) if !body.is_empty() => Some(header.len()),
+            _ => None
+        }).max().unwrap_or(0);
+        width[start..end].fill(max);
+        start = end;
+    }
+
+    let open = module.span.start + token_end(&source[module.span.clone()], CodeToken::CurlyOpen);
+    let mut res = with_comments(format!("@{}", module.name), &source[module.span.start..open]) + " {";
+    for (i, line) in lines.iter().enumerate() {
+        match breaks[i] {
+            0 => res.push(' '),
+            n => {
+                res += "\n".repeat(n-1).as_str();
+                res += "\n\t";
+            }
+        }
+        if let Line::Definition(comments, _, _) = line {
+            for comment in comments {
+                res += comment;
+                res += "\n\t";
+            }
+        }
+        match line {
+            Line::Definition(_, header, body) if body.is_empty() => res += header,
+            Line::Definition(_, header, body) => res += format!("{:<width$} {}", header, body, width = width[i]).as_str(),
+            Line::Other(text) => res += text
+        }
+    }
+    res + "\n}"
+}
+
+/// Re-emits the modules of a document in the canonical layout.
+/// C code between the modules is copied byte for byte.
+pub fn format_document(source: &str, sections: &[Section]) -> String
+{
+    let mut res = String::new();
+    let mut copied = 0;
+    for section in sections {
+        if let Section::MmgxModule(m) = section {
+            res += &source[copied..m.span.start];
+            res += module(source, m).as_str();
+            copied = m.span.end;
+        }
+    }
+    res + &source[copied..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::parse_source;
+    use crate::{compile::compile_sources, expand::same_tokens};
+
+    const SAMPLES: [(&str, &str); 3] = [
+        ("test.x", include_str!("../../../test.x")),
+        ("test2.x", include_str!("../../../test2.x")),
+        ("comments.x", "int a; /* before */\n@M /* keep me */ {\n\t// first\n\tT</* t > */I>  (I) // trailing\n\t@impl T<1..2>   /* impl */\n\t@let N = (1+2)*3 // let\n\tF(a /* ) */, b) a b\n\t@export /* e */ G(x) {\n\t  x +\n\t  N\n\t}\n\n\n\t/* last */\n}\nint b;\n")
+    ];
+
+    fn format(name: &str, source: &str) -> String
+    {
+        format_document(source, &parse_source(name, source).unwrap())
+    }
+
+    #[test]
+    fn idempotent()
+    {
+        for (name, source) in SAMPLES {
+            let once = format(name, source);
+            assert_eq!(format(name, &once), once, "{}", name);
+        }
+    }
+
+    #[test]
+    fn keeps_comments()
+    {
+        for (name, source) in SAMPLES {
+            let formatted = format(name, source);
+            for comment in comments(source) {
+                assert!(formatted.contains(comment), "{} lost {}", name, comment);
+            }
+            assert_eq!(comments(source).len(), comments(&formatted).len(), "{}", name);
+        }
+        let formatted = format("comments.x", SAMPLES[2].1);
+        assert!(formatted.starts_with("int a; /* before */\n@M /* keep me */ {\n\t// first\n\t/* t > */\n\tT<I> (I) // trailing\n"), "{}", formatted);
+        assert!(formatted.ends_with("\n\n\t/* last */\n}\nint b;\n"), "{}", formatted);
+    }
+
+    #[test]
+    fn compiles_the_same()
+    {
+        let formatted: Vec<(&str, String)> = SAMPLES.iter().map(|(name, source)| (*name, format(name, source))).collect();
+        let before = compile_sources(SAMPLES).unwrap();
+        let after = compile_sources(formatted.iter().map(|(name, source)| (*name, source.as_str()))).unwrap();
+        for ((before, after), (name, _)) in before.iter().zip(after.iter()).zip(SAMPLES) {
+            assert!(same_tokens(before, after), "{}:\n{}\n{}", name, before, after);
+        }
+    }
+}
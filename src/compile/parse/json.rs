@@ -0,0 +1,138 @@
+use std::{ops::Range, path::Path};
+use crate::json::Json;
+use super::{BodyStatement, Command, Expr, MmgxModule, Object, Section, Statement, TemplateArgument, TemplateParameter, ValueSet};
+
+// The syntax tree as JSON, every node is an object with a "type"
+
+fn node(kind: &str, mut fields: Vec<(&str, Json)>) -> Json
+{
+    fields.insert(0, ("type", kind.into()));
+    Json::object(fields)
+}
+
+fn span(span: &Range<usize>) -> Json
+{
+    Json::object(vec![("start", span.start.into()), ("end", span.end.into())])
+}
+
+fn option(value: Option<Json>) -> Json
+{
+    value.unwrap_or(Json::Null)
+}
+
+fn array<T>(items: impl Iterator<Item = T>, f: impl FnMut(T) -> Json) -> Json
+{
+    Json::Array(items.map(f).collect())
+}
+
+fn expr(e: &Expr) -> Json
+{
+    match e {
+        Expr::Number(num) => node("Number", vec![("value", (*num).into())]),
+        Expr::Variable(name) => node("Variable", vec![("name", name.as_str().into())]),
+        Expr::Unary(op, operand) => node("Unary", vec![("op", format!("{:?}", op).into()), ("operand", expr(operand))]),
+        Expr::Binary(op, lhs, rhs) => node("Binary", vec![("op", format!("{:?}", op).into()), ("lhs", expr(lhs)), ("rhs", expr(rhs))])
+    }
+}
+
+fn body_statement(statement: &BodyStatement, at: &Range<usize>) -> Json
+{
+    let (kind, mut fields) = match statement {
+        BodyStatement::Expand(text) => ("Expand", vec![("text", text.as_str().into())]),
+        BodyStatement::Parameter(index) => ("Parameter", vec![("index", (*index).into())]),
+        BodyStatement::OptDependency(name) => ("OptDependency", vec![("name", name.as_str().into())]),
+        BodyStatement::External(ext) => ("External", vec![
+            ("module", ext.module.as_str().into()),
+            ("object", ext.object.as_str().into()),
+            ("implement", ext.implement.into())
+        ]),
+        BodyStatement::TemplateCall(call) => ("TemplateCall", vec![
+            ("name", call.name.as_str().into()),
+            ("args", array(call.args.iter(), |arg| match arg {
+                TemplateArgument::Expr(e) => node("Expr", vec![("expr", expr(e))]),
+                TemplateArgument::Macro(name) => node("Macro", vec![("name", name.as_str().into())])
+            }))
+        ]),
+        BodyStatement::Eval(e) => ("Eval", vec![("expr", expr(e))])
+    };
+    fields.push(("span", span(at)));
+    node(kind, fields)
+}
+
+fn obj(kind: &str, obj: &Object, mut fields: Vec<(&str, Json)>) -> Json
+{
+    fields.insert(0, ("name", obj.name.as_str().into()));
+    fields.push(("body", array(obj.body.iter().zip(obj.spans.iter()), |(s, at)| body_statement(s, at))));
+    fields.push(("span", span(&obj.span)));
+    node(kind, fields)
+}
+
+fn value_set(set: &ValueSet) -> Json
+{
+    match set {
+        ValueSet::Range { start, end, inclusive, step } => node("Range", vec![
+            ("start", expr(start)),
+            ("end", expr(end)),
+            ("inclusive", (*inclusive).into()),
+            ("step", option(step.as_ref().map(expr)))
+        ]),
+        ValueSet::List(list) => node("List", vec![("values", array(list.iter(), expr))])
+    }
+}
+
+fn statement(statement: &Statement, at: &Range<usize>) -> Json
+{
+    let (kind, mut fields) = match statement {
+        Statement::Object(o) => {return obj("Object", o, Vec::new());},
+        Statement::Template(temp) => {
+            let params = array(temp.params.iter(), |p| match p {
+                TemplateParameter::Param(name) => node("Param", vec![("name", name.as_str().into())]),
+                TemplateParameter::Reference(index) => node("Reference", vec![("index", (*index).into())])
+            });
+            return obj("Template", &temp.obj, vec![("params", params)]);
+        },
+        Statement::Command(Command::Impl(cmd_impl)) => ("Impl", vec![
+            ("template", cmd_impl.template.as_str().into()),
+            ("params", array(cmd_impl.params.iter(), value_set)),
+            ("condition", option(cmd_impl.condition.as_ref().map(expr)))
+        ]),
+        Statement::Command(Command::Let(cmd_let)) => ("Let", vec![("name", cmd_let.name.as_str().into()), ("value", expr(&cmd_let.value))]),
+        Statement::Command(Command::Use(name)) => ("Use", vec![("module", name.as_str().into())]),
+        Statement::Command(Command::Import(path)) => ("Import", vec![("path", path.as_str().into())]),
+        Statement::Command(Command::Test(test)) => ("Test", vec![("expression", test.expression.as_str().into()), ("expected", test.expected.as_str().into())]),
+        Statement::Command(Command::Export(o)) => ("Export", vec![("object", obj("Object", o, Vec::new()))]),
+        Statement::Comment(text) => ("Comment", vec![("text", text.as_str().into())])
+    };
+    fields.push(("span", span(at)));
+    node(kind, fields)
+}
+
+fn module(module: &MmgxModule) -> Json
+{
+    node("MmgxModule", vec![
+        ("name", module.name.as_str().into()),
+        ("line", module.line.into()),
+        ("body", array(module.body.iter().zip(module.spans.iter()), |(s, at)| statement(s, at))),
+        ("span", span(&module.span))
+    ])
+}
+
+fn sections(sections: &[Section]) -> Json
+{
+    array(sections.iter(), |section| match section {
+        Section::CSource(text, at) => node("CSource", vec![("text", text.as_str().into()), ("span", span(at))]),
+        Section::MmgxModule(m) => module(m)
+    })
+}
+
+/// The sections of a document as a JSON array, source ranges are byte offsets
+pub fn sections_json(sections: &[Section]) -> String
+{
+    self::sections(sections).to_string()
+}
+
+/// A document as a JSON object holding its path and sections
+pub fn document_json(path: &Path, sections: &[Section]) -> String
+{
+    Json::object(vec![("path", path.to_string_lossy().as_ref().into()), ("sections", self::sections(sections))]).to_string()
+}
@@ -0,0 +1,569 @@
+use std::{collections::{BTreeSet, HashMap, VecDeque}, fmt::{Display, Formatter}, rc::Rc};
+
+// A C preprocessor reduced to macro replacement, following the hide set algorithm of Dave Prosser.
+// A name in the hide set of a token is painted blue, it never expands again.
+
+// Expansion is given up after this many replacements
+const MAX_STEPS: usize = 100000;
+
+const PUNCTUATORS: [&str; 23] = ["...", "<<=", ">>=", "##", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=", "%=", "+=", "-=", "&=", "^=", "|="];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Identifier,
+    Number,
+    Literal,
+    Punctuator,
+    NewLine,
+    // stands in for an empty argument next to '##'
+    Placemarker
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: Kind,
+    text: String,
+    // whitespace in front of the token
+    space: bool,
+    hide: BTreeSet<String>
+}
+
+impl Token {
+
+    fn is(&self, punctuator: &str) -> bool
+    {
+        self.kind == Kind::Punctuator && self.text == punctuator
+    }
+}
+
+struct Macro {
+    // None for object-like macros, a trailing '...' is named __VA_ARGS__
+    params: Option<Vec<String>>,
+    variadic: bool,
+    body: Vec<Token>
+}
+
+/// One replacement made while expanding
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// 0 for the expression itself, deeper levels expand macro arguments
+    pub depth: usize,
+    /// The macro that was replaced
+    pub name: String,
+    /// The tokens being expanded after the replacement
+    pub text: String
+}
+
+#[derive(Debug)]
+pub struct ExpandError(String);
+
+impl Display for ExpandError {
+
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        f.write_str(&self.0)
+    }
+}
+
+fn lex(text: &str) -> Vec<Token>
+{
+    // backslash newlines join lines before anything else
+    let text = text.replace("\\\r\n", "").replace("\\\n", "");
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut space = false;
+    let mut i = 0;
+
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let kind = match c {
+            ' ' | '\t' | '\r' | '\x0c' => {
+                space = true;
+                i+=1;
+                continue;
+            },
+            '\n' => {
+                i+=1;
+                Kind::NewLine
+            },
+            '/' if chars.get(i+1) == Some(&'/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i+=1;
+                }
+                space = true;
+                continue;
+            },
+            '/' if chars.get(i+1) == Some(&'*') => {
+                i+=2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i+1) == Some(&'/')) {
+                    i+=1;
+                }
+                i = (i+2).min(chars.len());
+                space = true;
+                continue;
+            },
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                while i < chars.len() && is_name(chars[i]) {
+                    i+=1;
+                }
+                Kind::Identifier
+            },
+            c if c.is_ascii_digit() || (c == '.' && chars.get(i+1).is_some_and(|c| c.is_ascii_digit())) => {
+                i+=1;
+                while i < chars.len() {
+                    match (chars[i], chars.get(i+1)) {
+                        ('e' | 'E' | 'p' | 'P', Some('+' | '-')) => i+=2,
+                        (c, _) if is_name(c) || c == '.' => i+=1,
+                        _ => break
+                    }
+                }
+                Kind::Number
+            },
+            '"' | '\'' => {
+                i+=1;
+                while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                    i += if chars[i] == '\\' {2} else {1};
+                }
+                i = (i+1).min(chars.len());
+                Kind::Literal
+            },
+            _ => {
+                let rest: String = chars[i..chars.len().min(i+3)].iter().collect();
+                i += PUNCTUATORS.iter().find(|p| rest.starts_with(*p)).map_or(1, |p| p.len());
+                Kind::Punctuator
+            }
+        };
+        tokens.push(Token { kind, text: chars[start..i].iter().collect(), space, hide: BTreeSet::new() });
+        space = false;
+    }
+    tokens
+}
+
+fn render(tokens: &[Token]) -> String
+{
+    let mut res = String::new();
+    for token in tokens.iter().filter(|t| t.kind != Kind::Placemarker) {
+        if token.space && !res.is_empty() {
+            res.push(' ');
+        }
+        res += &token.text;
+    }
+    res
+}
+
+// '#' turns an argument into a string literal
+fn stringize(arg: &[Token], space: bool) -> Token
+{
+    let mut text = String::from("\"");
+    for (i, token) in arg.iter().enumerate() {
+        if token.space && i > 0 {
+            text.push(' ');
+        }
+        match token.kind {
+            Kind::Literal => text += &token.text.replace('\\', "\\\\").replace('"', "\\\""),
+            _ => text += &token.text
+        }
+    }
+    text.push('"');
+    Token { kind: Kind::Literal, text, space, hide: BTreeSet::new() }
+}
+
+// '##' joins two tokens into one
+fn paste(lhs: Token, rhs: &Token) -> Result<Token, ExpandError>
+{
+    if lhs.kind == Kind::Placemarker {
+        return Ok(rhs.clone());
+    }
+    if rhs.kind == Kind::Placemarker {
+        return Ok(lhs);
+    }
+
+    let text = lhs.text.clone() + &rhs.text;
+    let mut tokens = lex(&text);
+    match (tokens.pop(), tokens.is_empty()) {
+        (Some(mut token), true) => {
+            token.space = lhs.space;
+            token.hide = lhs.hide.intersection(&rhs.hide).cloned().collect();
+            Ok(token)
+        },
+        _ => Err(ExpandError(format!("pasting \"{}\" and \"{}\" does not give a valid preprocessing token", lhs.text, rhs.text)))
+    }
+}
+
+struct Expander {
+    macros: HashMap<String, Rc<Macro>>,
+    steps: Vec<Step>,
+    depth: usize
+}
+
+impl Expander {
+
+    // Reads the '#define' and '#undef' lines of C source, every other line is ignored
+    fn define(&mut self, source: &str)
+    {
+        let tokens = lex(source);
+        for line in tokens.split(|t| t.kind == Kind::NewLine) {
+            let (directive, name) = match line {
+                [hash, directive, name, ..] if hash.is("#") && name.kind == Kind::Identifier => (directive.text.as_str(), name.text.clone()),
+                _ => {continue;}
+            };
+            match directive {
+                "define" => {},
+                "undef" => {
+                    self.macros.remove(&name);
+                    continue;
+                },
+                _ => {continue;}
+            }
+
+            let mut rest = &line[3..];
+            let mut params = None;
+            let mut variadic = false;
+
+            // only a '(' right behind the name makes a function-like macro
+            if rest.first().is_some_and(|t| t.is("(") && !t.space) {
+                let close = match rest.iter().position(|t| t.is(")")) {
+                    Some(close) => close,
+                    None => {continue;}
+                };
+                let mut list = Vec::new();
+                for token in rest[1..close].iter().filter(|t| !t.is(",")) {
+                    if token.is("...") {
+                        variadic = true;
+                        list.push(String::from("__VA_ARGS__"));
+                    } else {
+                        list.push(token.text.clone());
+                    }
+                }
+                params = Some(list);
+                rest = &rest[close+1..];
+            }
+
+            let mut body = rest.to_vec();
+            if let Some(first) = body.first_mut() {
+                first.space = false;
+            }
+            self.macros.insert(name, Rc::new(Macro { params, variadic, body }));
+        }
+    }
+
+    // The arguments of an invocation, `rest` starts at its '('.
+    // Returns them with the closing ')', unexpanded.
+    fn arguments(name: &str, mac: &Macro, rest: &mut VecDeque<Token>) -> Result<(Vec<Vec<Token>>, Token), ExpandError>
+    {
+        let params = mac.params.as_ref().map_or(0, |p| p.len());
+        rest.pop_front();
+
+        let mut args = vec![Vec::new()];
+        let mut depth = 0;
+        let close = loop {
+            let token = match rest.pop_front() {
+                Some(token) => token,
+                None => {return Err(ExpandError(format!("unterminated argument list invoking macro \"{}\"", name)));}
+            };
+            if token.is(")") && depth == 0 {
+                break token;
+            }
+            // the variadic argument takes all remaining commas
+            if token.is(",") && depth == 0 && !(mac.variadic && args.len() == params) {
+                args.push(Vec::new());
+                continue;
+            }
+            if token.is("(") {
+                depth+=1;
+            } else if token.is(")") {
+                depth-=1;
+            }
+            if let Some(arg) = args.last_mut() {
+                arg.push(token);
+            }
+        };
+
+        if mac.variadic && args.len() == params-1 {
+            args.push(Vec::new());
+        }
+        // 'F()' passes one empty argument, which is none for a macro without parameters
+        if params == 0 && args.len() == 1 && args[0].is_empty() {
+            args.clear();
+        }
+        if args.len() != params {
+            let required = if mac.variadic {format!("at least {}", params-1)} else {params.to_string()};
+            return Err(ExpandError(format!("macro \"{}\" requires {} arguments, but {} given", name, required, args.len())));
+        }
+        for arg in args.iter_mut() {
+            if let Some(first) = arg.first_mut() {
+                first.space = false;
+            }
+        }
+        Ok((args, close))
+    }
+
+    // The body of a macro with its parameters replaced, the names in `hide` are painted blue
+    fn substitute(&mut self, mac: &Macro, args: &[Vec<Token>], hide: &BTreeSet<String>) -> Result<Vec<Token>, ExpandError>
+    {
+        let param = |t: &Token| match (&mac.params, t.kind) {
+            (Some(params), Kind::Identifier) => params.iter().position(|p| *p == t.text),
+            _ => None
+        };
+
+        let mut res: Vec<Token> = Vec::new();
+        let mut i = 0;
+        while i < mac.body.len() {
+            let token = &mac.body[i];
+
+            if token.is("#") && mac.params.is_some() {
+                if let Some(p) = mac.body.get(i+1).and_then(param) {
+                    res.push(stringize(&args[p], token.space));
+                    i+=2;
+                    continue;
+                }
+            }
+
+            if token.is("##") {
+                let rhs = match mac.body.get(i+1) {
+                    Some(next) => match param(next) {
+                        Some(p) => args[p].clone(),
+                        None => vec![next.clone()]
+                    },
+                    None => Vec::new()
+                };
+                i+=2;
+                let mut rhs = rhs.into_iter();
+                match (res.pop(), rhs.next()) {
+                    (Some(lhs), Some(first)) => res.push(paste(lhs, &first)?),
+                    (lhs, first) => res.extend(lhs.into_iter().chain(first))
+                }
+                res.extend(rhs);
+                continue;
+            }
+
+            match param(token) {
+                // operands of '##' are not expanded
+                Some(p) if mac.body.get(i+1).is_some_and(|t| t.is("##")) => {
+                    if args[p].is_empty() {
+                        res.push(Token { kind: Kind::Placemarker, text: String::new(), space: token.space, hide: BTreeSet::new() });
+                    } else {
+                        let start = res.len();
+                        res.extend(args[p].iter().cloned());
+                        res[start].space = token.space;
+                    }
+                },
+                Some(p) => {
+                    self.depth+=1;
+                    let expanded = self.expand(args[p].iter().cloned().collect());
+                    self.depth-=1;
+                    let start = res.len();
+                    res.extend(expanded?);
+                    if let Some(first) = res.get_mut(start) {
+                        first.space = token.space;
+                    }
+                },
+                None => res.push(token.clone())
+            }
+            i+=1;
+        }
+
+        res.retain(|t| t.kind != Kind::Placemarker);
+        for token in res.iter_mut() {
+            token.hide.extend(hide.iter().cloned());
+        }
+        Ok(res)
+    }
+
+    fn expand(&mut self, tokens: VecDeque<Token>) -> Result<Vec<Token>, ExpandError>
+    {
+        let mut done = Vec::new();
+        let mut rest = tokens;
+
+        while let Some(token) = rest.pop_front() {
+            let mac = match self.macros.get(&token.text) {
+                Some(mac) if token.kind == Kind::Identifier && !token.hide.contains(&token.text) => mac.clone(),
+                _ => {
+                    done.push(token);
+                    continue;
+                }
+            };
+
+            let mut hide = token.hide.clone();
+            let args = match mac.params {
+                None => Vec::new(),
+                Some(_) => {
+                    // a function-like macro without arguments is just a name
+                    if !rest.front().is_some_and(|t| t.is("(")) {
+                        done.push(token);
+                        continue;
+                    }
+                    let (args, close) = Self::arguments(&token.text, &mac, &mut rest)?;
+                    hide = hide.intersection(&close.hide).cloned().collect();
+                    args
+                }
+            };
+            hide.insert(token.text.clone());
+
+            let mut replacement = self.substitute(&mac, &args, &hide)?;
+            if let Some(first) = replacement.first_mut() {
+                first.space = token.space;
+            }
+            for t in replacement.into_iter().rev() {
+                rest.push_front(t);
+            }
+
+            if self.steps.len() >= MAX_STEPS {
+                return Err(ExpandError(format!("gave up after {} replacements", MAX_STEPS)));
+            }
+            let text = render(&done) + if !done.is_empty() && rest.front().is_some_and(|t| t.space) {" "} else {""} + &render(rest.make_contiguous());
+            self.steps.push(Step { depth: self.depth, name: token.text, text });
+        }
+        Ok(done)
+    }
+}
+
+/// Expands `expression` with the macros defined in `source`, like the C preprocessor would.
+/// Returns every replacement made on the way and the final text.
+pub fn expand_macros(source: &str, expression: &str) -> Result<(Vec<Step>, String), ExpandError>
+{
+    let mut expander = Expander { macros: HashMap::new(), steps: Vec::new(), depth: 0 };
+    expander.define(source);
+
+    // line breaks in the expression are just whitespace
+    let mut tokens = VecDeque::new();
+    let mut space = false;
+    for mut token in lex(expression) {
+        if token.kind == Kind::NewLine {
+            space = true;
+            continue;
+        }
+        token.space |= space;
+        space = false;
+        tokens.push_back(token);
+    }
+    let res = expander.expand(tokens)?;
+    Ok((expander.steps, render(&res)))
+}
+
+/// Whether two pieces of C code are the same tokens, whitespace and comments aside
+pub fn same_tokens(a: &str, b: &str) -> bool
+{
+    let texts = |text: &str| lex(text).into_iter()
+        .filter(|t| t.kind != Kind::NewLine)
+        .map(|t| t.text)
+        .collect::<Vec<_>>();
+    texts(a) == texts(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expands(source: &str, expression: &str, expected: &str)
+    {
+        let (_, res) = expand_macros(source, expression).unwrap();
+        assert!(same_tokens(&res, expected), "{}\nexpanded to\n{}\nexpected\n{}", expression, res, expected);
+    }
+
+    // C99 6.10.3.5, example 3
+    #[test]
+    fn redefinition_and_rescanning()
+    {
+        let source = "#define x 3
+#define f(a) f(x * (a))
+#undef x
+#define x 2
+#define g f
+#define z z[0]
+#define h g(~
+#define m(a) a(w)
+#define w 0,1
+#define t(a) a
+#define p() int
+#define q(x) x
+#define r(x,y) x ## y
+#define str(x) # x
+";
+        expands(source, "f(y+1) + f(f(z)) % t(t(g)(0) + t)(1);", "f(2 * (y+1)) + f(2 * (f(2 * (z[0])))) % f(2 * (0)) + t(1);");
+        expands(source, "g(x+(3,4)-w) | h 5) & m\n(f)^m(m);", "f(2 * (2+(3,4)-0,1)) | f(2 * (~ 5)) & f(2 * (0,1))^m(0,1);");
+        expands(source, "p() i[q()] = { q(1), r(2,3), r(4,), r(,5), r(,) };", "int i[] = { 1, 23, 4, 5, };");
+        expands(source, "char c[2][6] = { str(hello), str() };", r#"char c[2][6] = { "hello", "" };"#);
+    }
+
+    // C99 6.10.3.5, example 4
+    #[test]
+    fn stringizing_and_pasting()
+    {
+        let source = r#"#define str(s) # s
+#define xstr(s) str(s)
+#define debug(s, t) printf("x" # s "= %d, x" # t "= %s", \
+ x ## s, x ## t)
+#define INCFILE(n) vers ## n
+#define glue(a, b) a ## b
+#define xglue(a, b) glue(a, b)
+#define HIGHLOW "hello"
+#define LOW LOW ", world"
+"#;
+        expands(source, "debug(1, 2);", r#"printf("x" "1" "= %d, x" "2" "= %s", x1, x2);"#);
+        expands(source, "fputs(str(strncmp(\"abc\\0d\", \"abc\", '\\4') // this goes away\n == 0) str(: @\\n), s);",
+            r#"fputs("strncmp(\"abc\\0d\", \"abc\", '\\4') == 0" ": @\n", s);"#);
+        expands(source, "xstr(INCFILE(2).h)", r#""vers2.h""#);
+        expands(source, "glue(HIGH, LOW);", r#""hello";"#);
+        expands(source, "xglue(HIGH, LOW)", r#""hello" ", world""#);
+    }
+
+    // C99 6.10.3.5, example 5
+    #[test]
+    fn placemarkers()
+    {
+        expands("#define t(x,y,z) x ## y ## z\n", "int j[] = { t(1,2,3), t(,4,5), t(6,,7), t(8,9,),\n t(10,,), t(,11,), t(,,12), t(,,) };",
+            "int j[] = { 123, 45, 67, 89, 10, 11, 12, };");
+    }
+
+    // C99 6.10.3.5, example 7
+    #[test]
+    fn variadic()
+    {
+        let source = "#define debug(...) fprintf(stderr, __VA_ARGS__)
+#define showlist(...) puts(#__VA_ARGS__)
+#define report(test, ...) ((test)?puts(#test): printf(__VA_ARGS__))
+";
+        expands(source, r#"debug("Flag");"#, r#"fprintf(stderr, "Flag");"#);
+        expands(source, r#"debug("X = %d\n", x);"#, r#"fprintf(stderr, "X = %d\n", x);"#);
+        expands(source, "showlist(The first, second, and third items.);", r#"puts("The first, second, and third items.");"#);
+        expands(source, r#"report(x>y, "x is %d but y is %d", x, y);"#, r#"((x>y)?puts("x>y"): printf("x is %d but y is %d", x, y));"#);
+    }
+
+    #[test]
+    fn self_reference()
+    {
+        expands("#define foo foo\n", "foo", "foo");
+        expands("#define foo a foo b\n", "foo", "a foo b");
+        let source = "#define x (4 + y)\n#define y (2 * x)\n";
+        expands(source, "x", "(4 + (2 * x))");
+        expands(source, "y", "(2 * (4 + y))");
+        // C99 6.10.3.4, the name is only hidden inside its own replacement
+        expands("#define f(a) a*g\n#define g(a) f(a)\n", "f(2)(9)", "2*9*g");
+        expands("#define f(x) x\n", "f(f)(1)", "f(1)");
+    }
+
+    #[test]
+    fn steps()
+    {
+        let (steps, res) = expand_macros("#define ONE 1\n#define ADD(a,b) a+b\n", "ADD(ONE, 2)").unwrap();
+        assert_eq!(res, "1+2");
+        let steps: Vec<_> = steps.iter().map(|step| (step.depth, step.name.as_str(), step.text.as_str())).collect();
+        assert_eq!(steps, [(1, "ONE", "1"), (0, "ADD", "1+2")]);
+    }
+
+    #[test]
+    fn errors()
+    {
+        let error = |source, expression| expand_macros(source, expression).unwrap_err().to_string();
+        assert_eq!(error("#define f(a,b) a\n", "f(1)"), "macro \"f\" requires 2 arguments, but 1 given");
+        assert_eq!(error("#define f(a,b,...) a\n", "f(1)"), "macro \"f\" requires at least 2 arguments, but 1 given");
+        assert_eq!(error("#define f(a) a\n", "f((1)"), "unterminated argument list invoking macro \"f\"");
+        // every level doubles the replacements, even though nothing is left in the end
+        let source: String = (0..20).map(|i| format!("#define M{} M{} M{}\n", i, i+1, i+1)).collect::<String>() + "#define M20\n";
+        assert_eq!(error(&source, "M0"), format!("gave up after {} replacements", MAX_STEPS));
+    }
+}
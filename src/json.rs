@@ -0,0 +1,332 @@
+use std::fmt::{Display, Formatter};
+
+// Just enough JSON for the syntax tree dump and JSON-RPC.
+// Integers are kept exact, other numbers as f64 like JavaScript does.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+static NULL: Json = Json::Null;
+
+impl Json {
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Self
+    {
+        Json::Object(fields.into_iter().map(|(name, value)| (String::from(name), value)).collect())
+    }
+
+    /// The field `key` of an object, null if there is none
+    pub fn get(&self, key: &str) -> &Json
+    {
+        match self {
+            Json::Object(fields) => fields.iter().find(|e| e.0 == key).map_or(&NULL, |e| &e.1),
+            _ => &NULL
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str>
+    {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize>
+    {
+        match self {
+            Json::Integer(num) => usize::try_from(*num).ok(),
+            Json::Number(num) if *num >= 0.0 && num.fract() == 0.0 => Some(*num as usize),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]>
+    {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self
+    {
+        Json::String(String::from(value))
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self
+    {
+        Json::String(value)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self
+    {
+        Json::Integer(value as i64)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Self
+    {
+        Json::Integer(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self
+    {
+        Json::Bool(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(value: Vec<Json>) -> Self
+    {
+        Json::Array(value)
+    }
+}
+
+fn write_string(f: &mut Formatter, s: &str) -> std::fmt::Result
+{
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    f.write_str("\"")
+}
+
+impl Display for Json {
+
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Integer(num) => write!(f, "{}", num),
+            Json::Number(num) if num.fract() == 0.0 && num.abs() < 1e15 => write!(f, "{}", *num as i64),
+            Json::Number(num) => write!(f, "{}", num),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            },
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    index: usize
+}
+
+impl<'a> Parser<'a> {
+
+    fn whitespace(&mut self)
+    {
+        while matches!(self.text.get(self.index), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.index+=1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> Option<()>
+    {
+        self.whitespace();
+        if self.text.get(self.index) == Some(&c) {
+            self.index+=1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Option<Json>
+    {
+        if self.text[self.index..].starts_with(word.as_bytes()) {
+            self.index+=word.len();
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn value(&mut self) -> Option<Json>
+    {
+        self.whitespace();
+        match self.text.get(self.index)? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.index+=1;
+                let mut items = Vec::new();
+                if self.eat(b']').is_some() {
+                    return Some(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.eat(b']').is_some() {
+                        return Some(Json::Array(items));
+                    }
+                    self.eat(b',')?;
+                }
+            },
+            b'{' => {
+                self.index+=1;
+                let mut fields = Vec::new();
+                if self.eat(b'}').is_some() {
+                    return Some(Json::Object(fields));
+                }
+                loop {
+                    self.whitespace();
+                    let name = self.string()?;
+                    self.eat(b':')?;
+                    fields.push((name, self.value()?));
+                    if self.eat(b'}').is_some() {
+                        return Some(Json::Object(fields));
+                    }
+                    self.eat(b',')?;
+                }
+            },
+            _ => self.number()
+        }
+    }
+
+    fn hex(&mut self) -> Option<u32>
+    {
+        let digits = std::str::from_utf8(self.text.get(self.index..self.index+4)?).ok()?;
+        self.index+=4;
+        u32::from_str_radix(digits, 16).ok()
+    }
+
+    fn string(&mut self) -> Option<String>
+    {
+        self.eat(b'"')?;
+        let mut res = Vec::new();
+        loop {
+            let c = *self.text.get(self.index)?;
+            self.index+=1;
+            match c {
+                b'"' => {return String::from_utf8(res).ok();},
+                b'\\' => {
+                    let c = *self.text.get(self.index)?;
+                    self.index+=1;
+                    match c {
+                        b'n' => res.push(b'\n'),
+                        b'r' => res.push(b'\r'),
+                        b't' => res.push(b'\t'),
+                        b'b' => res.push(0x08),
+                        b'f' => res.push(0x0c),
+                        b'u' => {
+                            let mut code = self.hex()?;
+                            // characters outside the BMP come as a surrogate pair
+                            if (0xd800..0xdc00).contains(&code) && self.text[self.index..].starts_with(b"\\u") {
+                                self.index+=2;
+                                let low = self.hex()?;
+                                code = if (0xdc00..0xe000).contains(&low) {0x10000 + ((code-0xd800) << 10) + (low-0xdc00)} else {0xfffd};
+                            }
+                            let c = char::from_u32(code).unwrap_or('\u{fffd}');
+                            res.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        },
+                        c => res.push(c)
+                    }
+                },
+                c => res.push(c)
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<Json>
+    {
+        let start = self.index;
+        while matches!(self.text.get(self.index), Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')) {
+            self.index+=1;
+        }
+        let text = std::str::from_utf8(&self.text[start..self.index]).ok()?;
+        match text.parse() {
+            Ok(num) => Some(Json::Integer(num)),
+            Err(_) => text.parse().ok().map(Json::Number)
+        }
+    }
+}
+
+/// Parses a complete JSON document, None if it is malformed
+pub fn parse(text: &str) -> Option<Json>
+{
+    let mut parser = Parser { text: text.as_bytes(), index: 0 };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.index == parser.text.len() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip()
+    {
+        let text = r#"{"id":9007199254740993,"x":-1.5,"s":"a\"b\\c\n\u0001","l":[true,false,null],"o":{}}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("id"), &Json::Integer(9007199254740993));
+        assert_eq!(value.get("s").as_str(), Some("a\"b\\c\n\u{1}"));
+        assert_eq!(value.to_string(), text);
+    }
+
+    #[test]
+    fn surrogate_pairs()
+    {
+        assert_eq!(parse(r#""\ud83d\ude00""#), Some(Json::String(String::from("\u{1f600}"))));
+    }
+
+    #[test]
+    fn malformed()
+    {
+        for text in ["", "{", "[1,]", "{\"a\" 1}", "nul", "1 2", "\"open"] {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! Compiles `.x` files, C sources with embedded `@Module { ... }` blocks, into plain C macros.
+//!
+//! [`parse_source`] parses a document, [`compile_modules`] turns parsed documents into C.
+//! [`compile_sources`] does both for documents in memory, [`files`] for files on disk the way the `mmgx` command does.
+//! [`Build`] wraps [`files`] for `build.rs` scripts.
+//! [`format_source`] and [`format_files`] lay modules out in the canonical style of `mmgx fmt`.
+//! [`serve_lsp`] runs the language server of `mmgx lsp`.
+//! [`expand_macros`] replays the C preprocessor on generated macros, [`expand_files`] on the output of `.x` files.
+//! [`test_files`] checks the `@test EXPR => EXPECTED` commands of modules that way, names of the module in `EXPR` stand for its macros.
+
+mod compile;
+mod build;
+mod lsp;
+mod expand;
+mod json;
+
+pub use build::Build;
+pub use lsp::serve as serve_lsp;
+pub use expand::{expand_macros, same_tokens, Step, ExpandError};
+pub use compile::{files, compile_modules, compile_sources, dump_ast, format_source, format_files, expand_files, test_files, is_stdin, Options, Generated, TestResult};
+pub use compile::{MmgxError, MmgxErrorKind, Diagnostic, CompileError, CompileErrorKind, EXIT_COMPILE_ERROR, EXIT_PARSE_ERROR, EXIT_IO_ERROR, EXPRESSION_PATH};
+pub use compile::parse::{parse_str, parse_source, ParseError, ParseErrorKind, UnexpectedToken, WrongArgument, CodeToken};
+
+/// The syntax tree of a parsed document
+pub mod ast {
+    pub use crate::compile::parse::{Section, MmgxModule, Statement, Command, CommandImpl, CommandLet, CommandTest, ValueSet};
+    pub use crate::compile::parse::{Object, Template, TemplateParameter, BodyStatement, External, TemplateCall, TemplateArgument};
+    pub use crate::compile::parse::{Expr, UnaryOp, BinaryOp};
+    pub use crate::compile::parse::{sections_json, document_json};
+}
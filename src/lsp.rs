@@ -0,0 +1,681 @@
+
+use std::{collections::HashMap, fs::read_to_string, io::{self, BufRead, Write}, ops::Range, path::{Path, PathBuf}, rc::Rc};
+use crate::json::{self, Json};
+use crate::compile::{self, parse, MmgxError, MmgxErrorKind};
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// LSP completion item kinds
+const KIND_FUNCTION: usize = 3;
+const KIND_CONSTANT: usize = 21;
+
+// a template expands to one macro per implementation, hover only shows the first ones
+const HOVER_LINES: usize = 20;
+
+struct Document {
+    text: String,
+    // the last text that parsed, completion keeps working while the current one is incomplete
+    parsed: Option<(String, Vec<parse::Section>)>
+}
+
+impl Document {
+
+    // the syntax tree, if it belongs to the current text
+    fn sections(&self) -> Option<&[parse::Section]>
+    {
+        match &self.parsed {
+            Some((text, sections)) if *text == self.text => Some(sections),
+            _ => None
+        }
+    }
+}
+
+// A file providing modules, the document itself or a file it imports
+struct Source {
+    uri: String,
+    text: String,
+    sections: Vec<parse::Section>
+}
+
+// What the cursor points at
+enum Target {
+    Module(String),
+    // module and object
+    Object(String, String)
+}
+
+fn uri_path(uri: &str) -> PathBuf
+{
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = path.as_bytes();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], path.get(i+1..i+3).and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                res.push(byte);
+                i+=3;
+            },
+            (byte, _) => {
+                res.push(byte);
+                i+=1;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&res).into_owned())
+}
+
+fn path_uri(path: &Path) -> String
+{
+    let path = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => std::env::current_dir().map_or_else(|_| path.to_path_buf(), |dir| dir.join(path))
+    };
+    let mut res = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => res.push(byte as char),
+            _ => res += format!("%{:02X}", byte).as_str()
+        }
+    }
+    res
+}
+
+// LSP counts characters in UTF-16 code units
+fn position(text: &str, offset: usize) -> Json
+{
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset-=1;
+    }
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i+1);
+    Json::object(vec![
+        ("line", before.matches('\n').count().into()),
+        ("character", before[line_start..].encode_utf16().count().into())
+    ])
+}
+
+fn offset(text: &str, position: &Json) -> Option<usize>
+{
+    let line = position.get("line").as_usize()?;
+    let character = position.get("character").as_usize()?;
+
+    let start = match line {
+        0 => 0,
+        line => text.match_indices('\n').nth(line-1)?.0 + 1
+    };
+    let mut units = 0;
+    for (i, c) in text[start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(start+i);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+fn range(text: &str, span: &Range<usize>) -> Json
+{
+    Json::object(vec![("start", position(text, span.start)), ("end", position(text, span.end))])
+}
+
+fn contains(span: &Range<usize>, offset: usize) -> bool
+{
+    span.start <= offset && offset <= span.end
+}
+
+fn is_name_char(c: char) -> bool
+{
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn modules(sources: &[Source]) -> impl Iterator<Item = (&Source, &Rc<parse::MmgxModule>)>
+{
+    sources.iter().flat_map(|source| source.sections.iter().filter_map(move |sec| match sec {
+        parse::Section::MmgxModule(module) => Some((source, module)),
+        _ => None
+    }))
+}
+
+// The module the offset lies in
+fn enclosing(sections: &[parse::Section], offset: usize) -> Option<&parse::MmgxModule>
+{
+    sections.iter().find_map(|sec| match sec {
+        parse::Section::MmgxModule(module) if contains(&module.span, offset) => Some(module.as_ref()),
+        _ => None
+    })
+}
+
+// The statement defining `name` in a module, with its object
+fn definition<'a>(module: &'a parse::MmgxModule, name: &str) -> Option<(usize, &'a parse::Object)>
+{
+    module.body.iter().enumerate().find_map(|(i, statement)| match statement {
+        parse::Statement::Object(obj) | parse::Statement::Command(parse::Command::Export(obj)) => Some((i, obj)),
+        parse::Statement::Template(temp) => Some((i, &temp.obj)),
+        _ => None
+    }.filter(|(_, obj)| obj.name == name))
+}
+
+fn target(module: &parse::MmgxModule, offset: usize) -> Option<Target>
+{
+    let local = |name: &String| Some(Target::Object(module.name.clone(), name.clone()));
+
+    let (statement, _) = module.body.iter().zip(module.spans.iter()).find(|(_, span)| contains(span, offset))?;
+    let obj = match statement {
+        parse::Statement::Object(obj) | parse::Statement::Command(parse::Command::Export(obj)) => obj,
+        parse::Statement::Template(temp) => &temp.obj,
+        parse::Statement::Command(parse::Command::Use(name)) => {return Some(Target::Module(name.clone()));},
+        parse::Statement::Command(parse::Command::Impl(cmd_impl)) => {return local(&cmd_impl.template);},
+        _ => {return None;}
+    };
+
+    if contains(&(obj.span.start..obj.span.start+obj.name.len()), offset) {
+        return local(&obj.name);
+    }
+    let (statement, span) = obj.body.iter().zip(obj.spans.iter()).find(|(_, span)| contains(span, offset))?;
+    match statement {
+        // [MODULE].[OBJECT] or [MODULE]::[OBJECT]
+        parse::BodyStatement::External(ext) if offset <= span.start+ext.module.len() => Some(Target::Module(ext.module.clone())),
+        parse::BodyStatement::External(ext) => Some(Target::Object(ext.module.clone(), ext.object.clone())),
+        parse::BodyStatement::TemplateCall(call) => local(&call.name),
+        parse::BodyStatement::OptDependency(name) => local(name),
+        _ => None
+    }
+}
+
+// Where a target is defined
+fn locate<'a>(sources: &'a [Source], target: &Target) -> Option<(&'a Source, Range<usize>)>
+{
+    let find = |name: &str| modules(sources).find(|(_, module)| module.name == name);
+    match target {
+        Target::Module(name) => {
+            let (source, module) = find(name)?;
+            let start = module.span.start + source.text[module.span.start..].find(name.as_str())?;
+            Some((source, start..start+name.len()))
+        },
+        Target::Object(module, name) => {
+            let (source, module) = find(module)?;
+            let (_, obj) = definition(module, name)?;
+            Some((source, obj.span.start..obj.span.start+obj.name.len()))
+        }
+    }
+}
+
+// The document and the files it imports, as the compiler sees them
+fn sources(uri: &str, text: &str, sections: &[parse::Section], include_dirs: &[PathBuf]) -> Vec<Source>
+{
+    let path = uri_path(uri);
+    let files = vec![(&path, sections.to_vec())];
+    // broken imports are reported as diagnostics
+    let library = compile::import(&files, include_dirs, true).unwrap_or_default();
+
+    let mut res = vec![Source { uri: String::from(uri), text: String::from(text), sections: sections.to_vec() }];
+    for (path, sections) in library {
+        if let Ok(text) = read_to_string(&path) {
+            res.push(Source { uri: path_uri(&path), text, sections });
+        }
+    }
+    res
+}
+
+fn diagnostics(path: &Path, text: &str, errors: &MmgxError) -> Vec<Json>
+{
+    errors.diagnostics().iter().map(|diagnostic| {
+        let (span, message) = match diagnostic.kind() {
+            MmgxErrorKind::ParseError(e) if diagnostic.path() == path => (e.span(), e.kind().to_string()),
+            MmgxErrorKind::CompileError(e) if diagnostic.path() == path => (e.span(), e.kind().to_string()),
+            // problems in other files are pinned to the start of the document
+            _ => (0..0, diagnostic.to_string())
+        };
+        Json::object(vec![
+            ("range", range(text, &span)),
+            ("severity", 1usize.into()),
+            ("source", "mmgx".into()),
+            ("message", message.trim_end().into())
+        ])
+    }).collect()
+}
+
+// Compiles a parsed document with its imports, the way the command line would
+fn check(path: &Path, text: &str, sections: &[parse::Section], include_dirs: &[PathBuf]) -> Vec<Json>
+{
+    let path = PathBuf::from(path);
+    let files = vec![(&path, sections.to_vec())];
+    match compile::import(&files, include_dirs, true).and_then(|library| compile::compile(&files, &library, &[], false, false)) {
+        Ok(_) => Vec::new(),
+        Err(errors) => diagnostics(&path, text, &errors)
+    }
+}
+
+fn notification(method: &str, params: Json) -> Json
+{
+    Json::object(vec![("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)])
+}
+
+fn response(id: &Json, result: Result<Json, (i64, String)>) -> Json
+{
+    let (name, value) = match result {
+        Ok(result) => ("result", result),
+        Err((code, message)) => ("error", Json::object(vec![("code", code.into()), ("message", message.into())]))
+    };
+    Json::object(vec![("jsonrpc", "2.0".into()), ("id", id.clone()), (name, value)])
+}
+
+fn publish(uri: &str, diagnostics: Vec<Json>) -> Json
+{
+    notification("textDocument/publishDiagnostics", Json::object(vec![("uri", uri.into()), ("diagnostics", diagnostics.into())]))
+}
+
+fn capabilities() -> Json
+{
+    Json::object(vec![
+        ("capabilities", Json::object(vec![
+            // full text on every change
+            ("textDocumentSync", 1usize.into()),
+            ("definitionProvider", true.into()),
+            ("hoverProvider", true.into()),
+            ("completionProvider", Json::object(vec![("triggerCharacters", vec![".".into(), ":".into()].into())]))
+        ])),
+        ("serverInfo", Json::object(vec![("name", "mmgx".into()), ("version", env!("CARGO_PKG_VERSION").into())]))
+    ])
+}
+
+struct Server {
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+    // the -I directories imports are searched in
+    include_dirs: Vec<PathBuf>
+}
+
+impl Server {
+
+    fn document(&self, params: &Json) -> Option<(&str, &Document)>
+    {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        self.documents.get_key_value(uri).map(|(uri, document)| (uri.as_str(), document))
+    }
+
+    fn update(&mut self, uri: &str, text: String) -> Json
+    {
+        let path = uri_path(uri);
+        let (diagnostics, parsed) = match parse::parse_x_source(&path, &text) {
+            Ok(sections) => (check(&path, &text, &sections, &self.include_dirs), Some((text.clone(), sections))),
+            Err(errors) => (diagnostics(&path, &text, &errors), None)
+        };
+
+        let document = self.documents.entry(String::from(uri)).or_insert(Document { text: String::new(), parsed: None });
+        document.text = text;
+        if parsed.is_some() {
+            document.parsed = parsed;
+        }
+        publish(uri, diagnostics)
+    }
+
+    fn definition(&self, params: &Json) -> Option<Json>
+    {
+        let (uri, document) = self.document(params)?;
+        let sections = document.sections()?;
+        let offset = offset(&document.text, params.get("position"))?;
+        let target = target(enclosing(sections, offset)?, offset)?;
+
+        let sources = sources(uri, &document.text, sections, &self.include_dirs);
+        let (source, span) = locate(&sources, &target)?;
+        Some(Json::object(vec![("uri", source.uri.as_str().into()), ("range", range(&source.text, &span))]))
+    }
+
+    fn hover(&self, params: &Json) -> Option<Json>
+    {
+        let (uri, document) = self.document(params)?;
+        let sections = document.sections()?;
+        let offset = offset(&document.text, params.get("position"))?;
+        let (module, name) = match target(enclosing(sections, offset)?, offset)? {
+            Target::Object(module, name) => (module, name),
+            Target::Module(_) => {return None;}
+        };
+
+        let sources = sources(uri, &document.text, sections, &self.include_dirs);
+        let modules: Vec<Rc<parse::MmgxModule>> = modules(&sources).map(|(_, module)| module.clone()).collect();
+        let module = modules.iter().find(|e| e.name == module)?;
+        let (index, _) = definition(module, &name)?;
+
+        // statements that fail to compile leave their part empty
+        let parts = module.compile_parts(&modules, &mut Vec::new());
+        let code: String = match module.body[index] {
+            // a template is defined by its implementations
+            parse::Statement::Template(_) => parts.iter().zip(module.body.iter())
+                .filter(|(_, statement)| matches!(statement, parse::Statement::Command(parse::Command::Impl(cmd_impl)) if cmd_impl.template == name))
+                .map(|(part, _)| part.1.as_str())
+                .collect(),
+            _ => parts.get(index)?.1.clone()
+        };
+
+        let lines: Vec<&str> = code.lines().filter(|line| !line.trim().is_empty()).collect();
+        if lines.is_empty() {
+            return None;
+        }
+        let mut code = lines[..lines.len().min(HOVER_LINES)].join("\n");
+        if lines.len() > HOVER_LINES {
+            code += "\n...";
+        }
+        Some(Json::object(vec![("contents", Json::object(vec![
+            ("kind", "markdown".into()),
+            ("value", format!("```c\n{}\n```", code).into())
+        ]))]))
+    }
+
+    // Objects of the modules the surrounding module uses, qualified unless the module was already typed
+    fn completion(&self, params: &Json) -> Option<Json>
+    {
+        let (uri, document) = self.document(params)?;
+        let (text, sections) = document.parsed.as_ref()?;
+        let offset = offset(&document.text, params.get("position"))?;
+        let module = enclosing(sections, offset)?;
+
+        let line = &document.text[document.text[..offset].rfind('\n').map_or(0, |i| i+1)..offset];
+        let typed = line.trim_end_matches(is_name_char);
+        let qualifier = typed.strip_suffix('.').or_else(|| typed.strip_suffix("::"))
+            .map(|q| &q[q.trim_end_matches(is_name_char).len()..])
+            .filter(|q| !q.is_empty());
+
+        let used: Vec<&String> = module.body.iter().filter_map(|statement| match statement {
+            parse::Statement::Command(parse::Command::Use(name)) => Some(name),
+            _ => None
+        }).collect();
+
+        let sources = sources(uri, text, sections, &self.include_dirs);
+        let mut seen = Vec::new();
+        let mut items = Vec::new();
+        for (_, module) in modules(&sources) {
+            // the first module of a name is the one '@use' resolves to
+            if !used.contains(&&module.name) || seen.contains(&&module.name) || qualifier.is_some_and(|q| q != module.name) {
+                continue;
+            }
+            seen.push(&module.name);
+
+            for statement in module.body.iter() {
+                let obj = match statement {
+                    parse::Statement::Object(obj) | parse::Statement::Command(parse::Command::Export(obj)) => obj,
+                    _ => {continue;}
+                };
+                let label = match qualifier {
+                    Some(_) => obj.name.clone(),
+                    None => format!("{}.{}", module.name, obj.name)
+                };
+                let (kind, detail) = match &obj.args {
+                    Some(args) => (KIND_FUNCTION, format!("{}({})", obj.name, args.join(", "))),
+                    None => (KIND_CONSTANT, obj.name.clone())
+                };
+                items.push(Json::object(vec![("label", label.into()), ("kind", kind.into()), ("detail", detail.into())]));
+            }
+        }
+        Some(items.into())
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)>
+    {
+        if self.shutdown {
+            return Err((INVALID_REQUEST, String::from("The server is shutting down")));
+        }
+        match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            },
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Json::Null)),
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Json::Null)),
+            "textDocument/completion" => Ok(self.completion(params).unwrap_or(Json::Array(Vec::new()))),
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method)))
+        }
+    }
+
+    // Notifications get no response, but may cause notifications of their own
+    fn notify(&mut self, method: &str, params: &Json) -> Option<Json>
+    {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        match method {
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").get("text").as_str()?;
+                Some(self.update(uri, String::from(text)))
+            },
+            "textDocument/didChange" => {
+                // with full sync the last change holds the whole text
+                let text = params.get("contentChanges").as_array()?.last()?.get("text").as_str()?;
+                Some(self.update(uri, String::from(text)))
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                Some(publish(uri, Vec::new()))
+            },
+            _ => None
+        }
+    }
+}
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>>
+{
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()>
+{
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Speaks the Language Server Protocol over `input` and `output` until the client sends `exit`.
+/// Returns whether the client asked for a shutdown before, as the protocol demands.
+/// Imports are searched in `include_dirs` like the `-I` directories of the compiler.
+pub fn serve(mut input: impl BufRead, mut output: impl Write, include_dirs: &[PathBuf]) -> io::Result<bool>
+{
+    let mut server = Server { documents: HashMap::new(), shutdown: false, include_dirs: include_dirs.to_vec() };
+
+    while let Some(body) = read_message(&mut input)? {
+        let message = match json::parse(&body) {
+            Some(message) => message,
+            None => {
+                write_message(&mut output, &response(&Json::Null, Err((PARSE_ERROR, String::from("Invalid JSON")))))?;
+                continue;
+            }
+        };
+        let method = message.get("method").as_str().unwrap_or_default();
+        let id = message.get("id");
+
+        if method == "exit" {
+            return Ok(server.shutdown);
+        }
+        let reply = match id {
+            Json::Null => server.notify(method, message.get("params")),
+            id => Some(response(id, server.request(method, message.get("params"))))
+        };
+        if let Some(reply) = reply {
+            write_message(&mut output, &reply)?;
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///tmp/doc.x";
+    const DOC: &str = "@LIB{\n\t@let N = 2\n\tONE 1\n\t@export ADD(a,b) ((a)+(b))\n\tTWICE<A> ADD(A,A)\n\t@impl TWICE<0..N>\n}\n\n@APP{\n\t@use LIB\n\tX LIB.ONE\n\tY LIB::ONE\n}\n";
+
+    fn server(text: &str) -> Server
+    {
+        let mut server = Server { documents: HashMap::new(), shutdown: false, include_dirs: Vec::new() };
+        let published = server.update(URI, String::from(text));
+        assert_eq!(published.get("params").get("diagnostics"), &Json::Array(Vec::new()));
+        server
+    }
+
+    fn at(line: usize, character: usize) -> Json
+    {
+        Json::object(vec![
+            ("textDocument", Json::object(vec![("uri", URI.into())])),
+            ("position", Json::object(vec![("line", line.into()), ("character", character.into())]))
+        ])
+    }
+
+    fn lines(range: &Json) -> (usize, usize, usize)
+    {
+        let (start, end) = (range.get("start"), range.get("end"));
+        assert_eq!(start.get("line"), end.get("line"));
+        (start.get("line").as_usize().unwrap(), start.get("character").as_usize().unwrap(), end.get("character").as_usize().unwrap())
+    }
+
+    fn message(body: &str) -> String
+    {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn messages(mut output: &[u8]) -> Vec<Json>
+    {
+        let mut res = Vec::new();
+        while let Some(body) = read_message(&mut output).unwrap() {
+            res.push(json::parse(&body).unwrap());
+        }
+        res
+    }
+
+    #[test]
+    fn framing()
+    {
+        let open = format!(r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"{}","text":{}}}}}}}"#, URI, Json::from(DOC));
+        let input: String = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            open.as_str(),
+            "{broken",
+            r#"{"jsonrpc":"2.0","id":"two","method":"unknown"}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"initialize"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#
+        ].into_iter().map(message).collect();
+        let mut output = Vec::new();
+        assert!(serve(input.as_bytes(), &mut output, &[]).unwrap());
+
+        let replies = messages(&output);
+        assert_eq!(replies.len(), 6);
+        assert_eq!(replies[0].get("id"), &Json::Integer(1));
+        assert_eq!(replies[0].get("result").get("capabilities").get("hoverProvider"), &Json::Bool(true));
+        assert_eq!(replies[1].get("method").as_str(), Some("textDocument/publishDiagnostics"));
+        assert_eq!(replies[1].get("params").get("uri").as_str(), Some(URI));
+        assert_eq!(replies[2].get("id"), &Json::Null);
+        assert_eq!(replies[2].get("error").get("code"), &Json::Integer(PARSE_ERROR));
+        assert_eq!(replies[3].get("id").as_str(), Some("two"));
+        assert_eq!(replies[3].get("error").get("code"), &Json::Integer(METHOD_NOT_FOUND));
+        assert_eq!(replies[4].get("result"), &Json::Null);
+        assert_eq!(replies[5].get("error").get("code"), &Json::Integer(INVALID_REQUEST));
+    }
+
+    #[test]
+    fn exit_without_shutdown()
+    {
+        let input = message(r#"{"jsonrpc":"2.0","method":"exit"}"#);
+        assert!(!serve(input.as_bytes(), &mut Vec::new(), &[]).unwrap());
+        // a closed input ends the session as well
+        assert!(!serve(&b""[..], &mut Vec::new(), &[]).unwrap());
+        assert!(serve(&b"Content-Type: x\r\n\r\n"[..], &mut Vec::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_error()
+    {
+        let mut server = Server { documents: HashMap::new(), shutdown: false, include_dirs: Vec::new() };
+        let published = server.update(URI, DOC.replace("Y LIB::ONE", "Y LIB::TWO"));
+        let diagnostics = published.get("params").get("diagnostics").as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(lines(diagnostics[0].get("range")).0, 11);
+    }
+
+    #[test]
+    fn imports_are_searched_in_the_include_dirs()
+    {
+        let dir = std::env::temp_dir().join(format!("mmgx-lsp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.x"), "@LIB{\n\tONE 1\n}\n").unwrap();
+        let text = String::from("@APP{\n\t@import \"lib.x\"\n\t@use LIB\n\tX LIB::ONE\n}\n");
+
+        let mut server = Server { documents: HashMap::new(), shutdown: false, include_dirs: Vec::new() };
+        let published = server.update(URI, text.clone());
+        assert_eq!(published.get("params").get("diagnostics").as_array().unwrap().len(), 1);
+
+        let mut server = Server { documents: HashMap::new(), shutdown: false, include_dirs: vec![dir.clone()] };
+        let published = server.update(URI, text);
+        assert_eq!(published.get("params").get("diagnostics"), &Json::Array(Vec::new()));
+        assert_eq!(server.definition(&at(3, 8)).unwrap().get("uri").as_str(), Some(path_uri(&dir.join("lib.x")).as_str()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn definitions()
+    {
+        let server = server(DOC);
+        let definition = |line, character| server.definition(&at(line, character)).map(|e| lines(e.get("range")));
+        // LIB.ONE, on the object and on the module
+        assert_eq!(definition(10, 8), Some((2, 1, 4)));
+        assert_eq!(definition(10, 4), Some((0, 1, 4)));
+        assert_eq!(definition(11, 9), Some((2, 1, 4)));
+        assert_eq!(definition(9, 7), Some((0, 1, 4)));
+        assert_eq!(definition(5, 9), Some((4, 1, 6)));
+        assert_eq!(definition(7, 0), None);
+        assert_eq!(server.definition(&at(10, 8)).unwrap().get("uri").as_str(), Some(URI));
+    }
+
+    #[test]
+    fn hovers()
+    {
+        let server = server(DOC);
+        let hover = |line, character| server.hover(&at(line, character))
+            .map(|e| String::from(e.get("contents").get("value").as_str().unwrap()));
+        assert_eq!(hover(10, 8).as_deref(), Some("```c\n#define __LIB_ONE 1\n```"));
+        let template = hover(5, 9).unwrap();
+        assert!(template.contains("#define __LIB_TWICE_0 LIB_ADD(0,0)"), "{}", template);
+        assert!(template.contains("#define __LIB_TWICE_2 LIB_ADD(2,2)"), "{}", template);
+        // modules have no code of their own
+        assert_eq!(hover(10, 4), None);
+    }
+
+    #[test]
+    fn completions()
+    {
+        let mut server = server(DOC);
+        let labels = |server: &Server, line, character| server.completion(&at(line, character)).unwrap()
+            .as_array().unwrap().iter()
+            .map(|item| String::from(item.get("label").as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(labels(&server, 11, 3), ["LIB.ONE", "LIB.ADD"]);
+        // only used modules are offered
+        assert!(labels(&server, 2, 5).is_empty());
+
+        // a typed module narrows the items to its objects
+        server.update(URI, DOC.replace("\tY LIB::ONE\n", "\tY LIB::ONE\n\tZ LIB::\n"));
+        assert_eq!(labels(&server, 12, 8), ["ONE", "ADD"]);
+    }
+}
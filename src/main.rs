@@ -1,22 +1,221 @@
-use std::{path::{Path,PathBuf}, vec};
-use clap::Parser;
-mod compile;
+use std::{path::PathBuf, process::ExitCode};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, error::ErrorKind};
+
+#[derive(Clone, ValueEnum)]
+enum AstFormat {
+    Json
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Reformat .x files in place, '-' formats stdin to stdout
+    Fmt {
+        /// Only report files that are not formatted
+        #[arg(long)]
+        check: bool,
+
+        files: Vec<PathBuf>
+    },
+    /// Run a language server over stdin and stdout
+    Lsp {
+        /// Search DIR for files named by '@import'
+        #[arg(short = 'I', value_name = "DIR")]
+        include: Vec<PathBuf>
+    },
+    /// Show each step the C preprocessor takes to expand a macro invocation
+    Expand {
+        /// Invocation to expand, like 'MGX_EQUAL(3,3)'
+        expression: String,
+
+        /// Files whose output defines the macros, '-' reads from stdin
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Search DIR for files named by '@import'
+        #[arg(short = 'I', value_name = "DIR")]
+        include: Vec<PathBuf>
+    },
+    /// Run the '@test' commands of the modules
+    Test {
+        /// Files with tests, '-' reads from stdin
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Search DIR for files named by '@import'
+        #[arg(short = 'I', value_name = "DIR")]
+        include: Vec<PathBuf>
+    }
+}
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(after_help = "Exit status: 0 on success, 1 on compile errors, failed tests or unformatted files with `fmt --check`, 2 on invalid arguments, 3 on parse errors, 4 on I/O errors")]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input files, '-' reads from stdin
     files: Vec<PathBuf>,
 
     #[arg(long, short, default_value_t = String::from("c"))]
-    extension: String
+    extension: String,
+
+    /// Write outputs into DIR, mirroring the directory layout of the inputs below their common directory
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    out_dir: Option<PathBuf>,
+
+    /// Write the output of a single input file to FILE
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Print the outputs to stdout instead of writing files
+    #[arg(long, conflicts_with_all = ["output", "out_dir"])]
+    stdout: bool,
+
+    /// Write a Make dependency file next to each output
+    #[arg(long = "MD")]
+    make_deps: bool,
+
+    /// Write all dependency rules to FILE
+    #[arg(long = "MF", value_name = "FILE", requires = "make_deps")]
+    dep_file: Option<PathBuf>,
+
+    /// Search DIR for files named by '@import'
+    #[arg(short = 'I', value_name = "DIR")]
+    include: Vec<PathBuf>,
+
+    /// Put the compiled modules into a header next to each output
+    #[arg(long, conflicts_with = "stdout")]
+    emit_header: bool,
+
+    /// Put the non-exported macros into a second '_private.h' header
+    #[arg(long, requires = "emit_header")]
+    private_header: bool,
+
+    /// Point the C compiler back into the '.x' files with '#line' directives
+    #[arg(long)]
+    line_directives: bool,
+
+    /// Print the syntax trees of the inputs instead of compiling them
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    dump_ast: Option<AstFormat>
+}
+
+// Make users know the dependency flags as '-MD' and '-MF' from C compilers
+fn normalize_arg(arg: String) -> String
+{
+    match arg.as_str() {
+        "-MD" | "-MF" => format!("-{}", arg),
+        _ => arg
+    }
+}
+
+// there is only one stdin to read
+fn check_stdin(files: &[PathBuf])
+{
+    if files.iter().filter(|path| mmgx::is_stdin(path)).count() > 1 {
+        Args::command().error(ErrorKind::ArgumentConflict, "stdin can only be read once").exit();
+    }
+}
+
+// Errors of every mode are printed the same way and exit with the code of their kind
+fn run<T>(result: Result<T, mmgx::MmgxError>, report: impl FnOnce(T) -> ExitCode) -> ExitCode
+{
+    match result {
+        Ok(value) => report(value),
+        Err(me) => {
+            eprintln!("An error occured: {}", me);
+            ExitCode::from(me.exit_code())
+        }
+    }
 }
 
+fn main() -> ExitCode {
 
-fn main() {
+    let args = Args::parse_from(std::env::args().map(normalize_arg));
 
-    let args = Args::parse();
+    if let Some(Command::Lsp { include }) = &args.command {
+        return match mmgx::serve_lsp(std::io::stdin().lock(), std::io::stdout().lock(), include) {
+            Ok(true) => ExitCode::SUCCESS,
+            // exiting without a shutdown request is an error by the protocol
+            Ok(false) => ExitCode::FAILURE,
+            Err(err) => {
+                eprintln!("An error occured: {}", err);
+                ExitCode::from(mmgx::EXIT_IO_ERROR)
+            }
+        };
+    }
 
-    match compile::files(args.files, args.extension){
-        Ok(()) => (),
-        Err(me) => println!("An error occured: {}", me)
+    if let Some(Command::Expand { expression, files, include }) = args.command {
+        check_stdin(&files);
+        return run(mmgx::expand_files(files, &include, &expression), |(steps, _)| {
+            println!("{}", expression.trim());
+            for step in steps {
+                println!("{}{}: {}", "  ".repeat(step.depth+1), step.name, step.text);
+            }
+            ExitCode::SUCCESS
+        });
+    }
+
+    if let Some(Command::Test { files, include }) = args.command {
+        check_stdin(&files);
+        return run(mmgx::test_files(files, &include), |results| {
+            let failed = results.iter().filter(|test| !test.passed()).count();
+            for test in results.iter() {
+                let location = test.location();
+                match (&test.got, test.passed()) {
+                    (_, true) => println!("{}: ok {} => {}", location, test.expression, test.expected),
+                    (Ok(got), false) => println!("{}: FAILED {} => {}, got {}", location, test.expression, test.expected, got),
+                    (Err(reason), false) => println!("{}: FAILED {} => {}, {}", location, test.expression, test.expected, reason)
+                }
+            }
+            println!("{} test{}, {} failed", results.len(), if results.len()==1 {""} else {"s"}, failed);
+            if failed == 0 {ExitCode::SUCCESS} else {ExitCode::FAILURE}
+        });
+    }
+
+    if let Some(Command::Fmt { check, files }) = args.command {
+        check_stdin(&files);
+        return run(mmgx::format_files(files, check), |changed| {
+            if !check || changed.is_empty() {
+                return ExitCode::SUCCESS;
+            }
+            for path in changed {
+                eprintln!("{} is not formatted", path.display());
+            }
+            ExitCode::FAILURE
+        });
+    }
+
+    if args.output.is_some() && args.files.len() != 1 {
+        Args::command().error(ErrorKind::ArgumentConflict, "--output requires exactly one input file").exit();
+    }
+
+    check_stdin(&args.files);
+
+    if args.emit_header && args.output.is_none() && args.files.iter().any(|path| mmgx::is_stdin(path)) {
+        Args::command().error(ErrorKind::ArgumentConflict, "--emit-header requires --output when reading stdin").exit();
+    }
+
+    if let Some(AstFormat::Json) = args.dump_ast {
+        return run(mmgx::dump_ast(args.files), |json| {
+            print!("{}", json);
+            ExitCode::SUCCESS
+        });
+    }
+
+    let options = mmgx::Options {
+        extension: args.extension,
+        out_dir: args.out_dir,
+        output: args.output,
+        stdout: args.stdout,
+        make_deps: args.make_deps,
+        dep_file: args.dep_file,
+        include_dirs: args.include,
+        emit_header: args.emit_header,
+        private_header: args.private_header,
+        line_directives: args.line_directives
     };
+
+    run(mmgx::files(args.files, &options), |_| ExitCode::SUCCESS)
 }
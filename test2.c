@@ -1,5 +1,6 @@
 
 
+
 #define __MGX_EQ_0_0 true
 #define __MGX_EQ_1_1 true
 #define __MGX_EQ_2_2 true
@@ -102,18 +103,57 @@
 #define __MGX_EQ_99_99 true
 #define __MGX_EQ_100_100 true
 
+#define __MGX_FOREACH_0(M, ...)
+
+
 
+#define __MGX_Add_0_0 0
+#define __MGX_Add_0_1 1
+#define __MGX_Add_0_2 2
+#define __MGX_Add_0_3 3
+#define __MGX_Add_0_4 4
+#define __MGX_Add_0_5 5
+#define __MGX_Add_1_0 1
+#define __MGX_Add_1_1 2
+#define __MGX_Add_1_2 3
+#define __MGX_Add_1_3 4
+#define __MGX_Add_1_4 5
+#define __MGX_Add_1_5 6
+#define __MGX_Add_2_0 2
+#define __MGX_Add_2_1 3
+#define __MGX_Add_2_2 4
+#define __MGX_Add_2_3 5
+#define __MGX_Add_2_4 6
+#define __MGX_Add_2_5 7
+#define __MGX_Add_3_0 3
+#define __MGX_Add_3_1 4
+#define __MGX_Add_3_2 5
+#define __MGX_Add_3_3 6
+#define __MGX_Add_3_4 7
+#define __MGX_Add_3_5 8
+#define __MGX_Add_4_0 4
+#define __MGX_Add_4_1 5
+#define __MGX_Add_4_2 6
+#define __MGX_Add_4_3 7
+#define __MGX_Add_4_4 8
+#define __MGX_Add_4_5 9
+#define __MGX_Add_5_0 5
+#define __MGX_Add_5_1 6
+#define __MGX_Add_5_2 7
+#define __MGX_Add_5_3 8
+#define __MGX_Add_5_4 9
+#define __MGX_Add_5_5 10
 
-#define __MGX_FOREACH_1(M, A, ...) M(A) __MGX_FOREACH<1-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_2(M, A, ...) M(A) __MGX_FOREACH<2-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_3(M, A, ...) M(A) __MGX_FOREACH<3-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_4(M, A, ...) M(A) __MGX_FOREACH<4-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_5(M, A, ...) M(A) __MGX_FOREACH<5-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_6(M, A, ...) M(A) __MGX_FOREACH<6-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_7(M, A, ...) M(A) __MGX_FOREACH<7-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_8(M, A, ...) M(A) __MGX_FOREACH<8-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_9(M, A, ...) M(A) __MGX_FOREACH<9-1>(M, __VA_ARGS__)
-#define __MGX_FOREACH_10(M, A, ...) M(A) __MGX_FOREACH<10-1>(M, __VA_ARGS__)
+#define __MGX_FOREACH_1(M, A, ...) M(A) __MGX_FOREACH_0(M, __VA_ARGS__)
+#define __MGX_FOREACH_2(M, A, ...) M(A) __MGX_FOREACH_1(M, __VA_ARGS__)
+#define __MGX_FOREACH_3(M, A, ...) M(A) __MGX_FOREACH_2(M, __VA_ARGS__)
+#define __MGX_FOREACH_4(M, A, ...) M(A) __MGX_FOREACH_3(M, __VA_ARGS__)
+#define __MGX_FOREACH_5(M, A, ...) M(A) __MGX_FOREACH_4(M, __VA_ARGS__)
+#define __MGX_FOREACH_6(M, A, ...) M(A) __MGX_FOREACH_5(M, __VA_ARGS__)
+#define __MGX_FOREACH_7(M, A, ...) M(A) __MGX_FOREACH_6(M, __VA_ARGS__)
+#define __MGX_FOREACH_8(M, A, ...) M(A) __MGX_FOREACH_7(M, __VA_ARGS__)
+#define __MGX_FOREACH_9(M, A, ...) M(A) __MGX_FOREACH_8(M, __VA_ARGS__)
+#define __MGX_FOREACH_10(M, A, ...) M(A) __MGX_FOREACH_9(M, __VA_ARGS__)
 
 #define MGX_EQUAL(A, B) __MGX_EQ##_##A##_##B
 
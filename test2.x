@@ -1,11 +1,15 @@
 
 @MGX{
+	@let MAX = 100
+
 	EQ<A,A> true
-	@impl EQ<0..100>
+	@impl EQ<0..MAX>
 
+	FOREACH_0(M, ...)
 	FOREACH<I>(M, A, ...) M(A) FOREACH<I-1>(M, ...)
 
 	Add<a,b> @eval(a+b)
+	@impl Add<0..5, 0..5>
 
 	@impl FOREACH<1..10>
 